@@ -5,3 +5,13 @@ load-music-failed = Failed to load music
 load-sfx-failed = Failed to load SFX `{ $name }`
 run-ffmpeg-failed = Failed to run ffmpeg
 no-hwacc = Hardware acceleration is unsupported!
+ffmpeg-not-found = FFmpeg not found
+invalid-config = Invalid render settings
+disk-full = Not enough disk space
+render-failed = Render failed
+
+hint-chart-load = Check that the chart file still exists and is a valid .zip/.pez or chart folder.
+hint-ffmpeg-missing = Install FFmpeg and make sure it is on PATH, or place ffmpeg next to Phi-TK.
+hint-encoder-unavailable = Update your GPU driver, pick another encoder, or turn off hardware acceleration.
+hint-invalid-config = Check the audio format, image format and codec settings of this preset.
+hint-disk-full = Free up space on the output drive or choose another output directory.
@@ -5,3 +5,13 @@ load-music-failed = 加载音乐失败
 load-sfx-failed = 加载音效 `{ $name }` 失败
 run-ffmpeg-failed = 运行 ffmpeg 失败
 no-hwacc = 不支持硬件加速
+ffmpeg-not-found = 未找到 FFmpeg
+invalid-config = 渲染设置无效
+disk-full = 磁盘空间不足
+render-failed = 渲染失败
+
+hint-chart-load = 请确认谱面文件仍然存在，并且是有效的 .zip/.pez 文件或谱面文件夹。
+hint-ffmpeg-missing = 请安装 FFmpeg 并确保其在 PATH 中，或将 ffmpeg 放在 Phi-TK 同目录下。
+hint-encoder-unavailable = 请更新显卡驱动、选择其他编码器，或关闭硬件加速。
+hint-invalid-config = 请检查该预设的音频格式、图片格式和编码器设置。
+hint-disk-full = 请清理输出所在磁盘的空间，或更换输出目录。
@@ -0,0 +1,305 @@
+//! Headless front end for `render`, e.g.
+//! `Phi-TK render --chart foo.pez --preset 1080p60 --out out.mp4 --assets /path`.
+//!
+//! The GUI keeps talking to `render` through stdin (see `render::main`), this module is only
+//! used when the first argument after `render` is a flag.
+
+use crate::{
+    common::{default_asset_dir, default_config_dir},
+    ipc,
+    render::{self, RenderConfig, RenderParams, RenderPart},
+};
+use anyhow::{bail, Context, Result};
+use macroquad::prelude::set_pc_assets_folder;
+use prpr::fs;
+use serde_json::Value;
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::BufReader,
+    ops::DerefMut,
+    path::PathBuf,
+};
+
+const USAGE: &str = "\
+Usage: Phi-TK render --chart <FILE> --out <FILE> [OPTIONS] [--<config-field> <VALUE>]...
+
+Options:
+  --chart <FILE>         Chart to render (.zip, .pez or a chart folder)
+  --out <FILE>           Output video file
+  --assets <DIR>         Asset directory (defaults to the installed assets)
+  --preset <NAME>        Start from a preset saved in presets.json
+  --presets-file <FILE>  Read presets from this file instead of the app config directory
+  --part <PART>          Render only part of the output, to split a render across machines:
+                         `audio` for the mixed audio track, or `slice:<N>/<COUNT>` for the
+                         N-th of COUNT video-only time ranges. Join the slices with ffmpeg's
+                         concat demuxer and mux them with the audio part afterwards
+  --list-presets         Print the available presets and exit
+  -h, --help             Print this help
+
+Every field of the render config can be overridden with a kebab-case flag, e.g.
+  --fps 120 --resolution 3840x2160 --video-codec hevc --encoder nvenc --hardware-accel false
+
+Exit codes: 0 on success, 1 if rendering failed, 2 on invalid arguments.";
+
+const EXIT_USAGE: i32 = 2;
+
+#[derive(Default)]
+struct CliArgs {
+    chart: Option<PathBuf>,
+    out: Option<PathBuf>,
+    assets: Option<PathBuf>,
+    preset: Option<String>,
+    presets_file: Option<PathBuf>,
+    part: Option<String>,
+    list_presets: bool,
+    help: bool,
+    overrides: Vec<(String, Option<String>)>,
+}
+
+impl CliArgs {
+    fn parse(args: impl IntoIterator<Item = String>) -> Result<Self> {
+        let mut result = Self::default();
+        let mut args = args.into_iter().peekable();
+        while let Some(arg) = args.next() {
+            let Some(flag) = arg.strip_prefix("--") else {
+                if arg == "-h" {
+                    result.help = true;
+                    continue;
+                }
+                bail!("unexpected argument `{arg}`");
+            };
+            let (flag, inline_value) = match flag.split_once('=') {
+                Some((flag, value)) => (flag.to_owned(), Some(value.to_owned())),
+                None => (flag.to_owned(), None),
+            };
+            let mut value = || -> Result<String> {
+                match &inline_value {
+                    Some(value) => Ok(value.clone()),
+                    None => args.next().with_context(|| format!("missing value for `--{flag}`")),
+                }
+            };
+            match flag.as_str() {
+                "chart" => result.chart = Some(value()?.into()),
+                "out" => result.out = Some(value()?.into()),
+                "assets" => result.assets = Some(value()?.into()),
+                "preset" => result.preset = Some(value()?),
+                "presets-file" => result.presets_file = Some(value()?.into()),
+                "part" => result.part = Some(value()?),
+                "list-presets" => result.list_presets = true,
+                "help" => result.help = true,
+                _ => {
+                    // A bare flag (`--fxaa`) is shorthand for `--fxaa true`
+                    let value = match inline_value {
+                        Some(value) => Some(value),
+                        None => match args.peek() {
+                            Some(next) if !next.starts_with("--") => args.next(),
+                            _ => None,
+                        },
+                    };
+                    result.overrides.push((flag, value));
+                }
+            }
+        }
+        Ok(result)
+    }
+}
+
+pub fn is_cli_invocation() -> bool {
+    match std::env::args().nth(2) {
+        Some(arg) => arg.starts_with('-'),
+        None => true,
+    }
+}
+
+fn kebab_to_camel(name: &str) -> String {
+    let mut result = String::with_capacity(name.len());
+    let mut upper = false;
+    for ch in name.chars() {
+        if ch == '-' || ch == '_' {
+            upper = true;
+        } else if upper {
+            result.extend(ch.to_uppercase());
+            upper = false;
+        } else {
+            result.push(ch);
+        }
+    }
+    result
+}
+
+/// Parses `value` into the JSON type that `current` already has, so that `--bitrate 28` stays a
+/// string while `--fps 120` becomes a number.
+fn parse_override(current: &Value, value: Option<&str>) -> Result<Value> {
+    let Some(value) = value else {
+        return match current {
+            Value::Bool(_) | Value::Null => Ok(Value::Bool(true)),
+            _ => bail!("a value is required"),
+        };
+    };
+    Ok(match current {
+        Value::String(_) => Value::String(value.to_owned()),
+        Value::Bool(_) => Value::Bool(match value {
+            "true" | "yes" | "on" | "1" => true,
+            "false" | "no" | "off" | "0" => false,
+            _ => bail!("expected a boolean, got `{value}`"),
+        }),
+        Value::Number(_) => {
+            let number: Value = serde_json::from_str(value).with_context(|| format!("expected a number, got `{value}`"))?;
+            if !number.is_number() {
+                bail!("expected a number, got `{value}`");
+            }
+            number
+        }
+        Value::Array(_) => {
+            if let Some((w, h)) = value.split_once(['x', 'X', ',']) {
+                let w: u32 = w.trim().parse().with_context(|| format!("invalid width `{w}`"))?;
+                let h: u32 = h.trim().parse().with_context(|| format!("invalid height `{h}`"))?;
+                serde_json::json!([w, h])
+            } else {
+                serde_json::from_str(value)?
+            }
+        }
+        // Optional fields and enums: accept JSON literals, fall back to a plain string
+        Value::Null | Value::Object(_) => serde_json::from_str(value).unwrap_or_else(|_| Value::String(value.to_owned())),
+    })
+}
+
+/// Parses `full`, `audio` or `slice:<N>/<COUNT>` with N counted from 1.
+fn parse_part(part: &str) -> Result<RenderPart> {
+    Ok(match part {
+        "full" => RenderPart::Full,
+        "audio" => RenderPart::Audio,
+        _ => {
+            let Some((index, count)) = part.strip_prefix("slice:").and_then(|it| it.split_once('/')) else {
+                bail!("expected `full`, `audio` or `slice:<N>/<COUNT>`, got `{part}`");
+            };
+            let index: u32 = index.trim().parse().with_context(|| format!("invalid slice number `{index}`"))?;
+            let count: u32 = count.trim().parse().with_context(|| format!("invalid slice count `{count}`"))?;
+            if index == 0 || index > count {
+                bail!("slice number must be between 1 and {count}, got {index}");
+            }
+            RenderPart::Slice { index: index - 1, count }
+        }
+    })
+}
+
+fn apply_overrides(config: RenderConfig, overrides: &[(String, Option<String>)]) -> Result<RenderConfig> {
+    let mut json = serde_json::to_value(config)?;
+    let fields = json.as_object_mut().unwrap();
+    for (flag, value) in overrides {
+        let key = kebab_to_camel(flag);
+        let Some(current) = fields.get(&key) else {
+            bail!("unknown option `--{flag}`");
+        };
+        let parsed = parse_override(current, value.as_deref()).with_context(|| format!("invalid value for `--{flag}`"))?;
+        fields.insert(key, parsed);
+    }
+    serde_json::from_value(json).context("invalid render config")
+}
+
+fn load_presets(file: Option<PathBuf>) -> Result<HashMap<String, RenderConfig>> {
+    let file = match file {
+        Some(file) => file,
+        None => match default_config_dir() {
+            Some(dir) => dir.join("presets.json"),
+            None => return Ok(HashMap::new()),
+        },
+    };
+    if !file.exists() {
+        return Ok(HashMap::new());
+    }
+    serde_json::from_reader(BufReader::new(File::open(&file)?)).with_context(|| format!("failed to read presets from {}", file.display()))
+}
+
+fn prepare(args: CliArgs) -> Result<Option<(RenderParams, PathBuf)>> {
+    let mut presets = load_presets(args.presets_file)?;
+    if args.list_presets {
+        let mut names: Vec<_> = presets.keys().collect();
+        names.sort();
+        for name in names {
+            println!("{name}");
+        }
+        return Ok(None);
+    }
+
+    let chart = args.chart.context("`--chart` is required")?;
+    let out = args.out.context("`--out` is required")?;
+
+    let config = match args.preset.as_deref() {
+        None | Some("default") => RenderConfig::default(),
+        Some(name) => presets.remove(name).with_context(|| format!("preset `{name}` not found"))?,
+    };
+    let config = apply_overrides(config, &args.overrides)?;
+    let part = match args.part.as_deref() {
+        Some(part) => parse_part(part).context("invalid value for `--part`")?,
+        None => RenderPart::Full,
+    };
+    if let RenderPart::Slice { .. } = part {
+        if !config.can_slice() {
+            bail!("only video outputs without transparency can be sliced");
+        }
+        if config.two_pass() {
+            bail!("two-pass encodes can't be sliced");
+        }
+    }
+
+    let assets = match args.assets {
+        Some(assets) => assets,
+        None => default_asset_dir()?,
+    };
+    if !assets.is_dir() {
+        bail!("asset directory {} does not exist", assets.display());
+    }
+    set_pc_assets_folder(&assets.display().to_string());
+
+    Ok(Some((
+        RenderParams {
+            path: chart,
+            info: Default::default(),
+            part,
+            encoders: None,
+            config,
+        },
+        out,
+    )))
+}
+
+pub async fn main() -> Result<()> {
+    let args = CliArgs::parse(std::env::args().skip(2)).unwrap_or_else(|err| {
+        eprintln!("error: {err:#}\n\n{USAGE}");
+        std::process::exit(EXIT_USAGE);
+    });
+    if args.help {
+        println!("{USAGE}");
+        return Ok(());
+    }
+    let Some((mut params, out)) = prepare(args).unwrap_or_else(|err| {
+        eprintln!("error: {err:#}");
+        std::process::exit(EXIT_USAGE);
+    }) else {
+        return Ok(());
+    };
+
+    let mut fs = fs::fs_from_file(&params.path).with_context(|| format!("failed to read chart {}", params.path.display()))?;
+    params.info = fs::load_info(fs.deref_mut()).await.context("failed to load chart info")?;
+    drop(fs);
+
+    if let Some(parent) = out.parent().filter(|it| !it.as_os_str().is_empty()) {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    // Nobody reads the event stream in headless mode
+    ipc::client::disable();
+    if params.config.two_pass() && params.part == RenderPart::Full {
+        let mut analysis = params.clone();
+        analysis.part = RenderPart::Analysis;
+        let result = match render::render(analysis, out.clone()).await {
+            Ok(()) => render::render(params, out.clone()).await,
+            Err(err) => Err(err),
+        };
+        let _ = render::remove_pass_logs(&out);
+        return result;
+    }
+    render::render(params, out).await
+}
@@ -4,6 +4,27 @@ use std::{path::PathBuf, sync::OnceLock};
 pub static CONFIG_DIR: OnceLock<PathBuf> = OnceLock::new();
 pub static DATA_DIR: OnceLock<PathBuf> = OnceLock::new();
 
+/// Must match `identifier` in `tauri.conf.json`, Tauri derives the app directories from it.
+pub const APP_IDENTIFIER: &str = "com.sc.phi.tk";
+
+/// Resolves the same directory as Tauri's `app_config_dir` without a running app.
+pub fn default_config_dir() -> Option<PathBuf> {
+    dirs::config_dir().map(|it| it.join(APP_IDENTIFIER))
+}
+
+pub fn default_asset_dir() -> Result<PathBuf> {
+    #[cfg(target_os = "linux")]
+    let asset_dir = {
+        PathBuf::from("/usr/lib/Phi-TK/assets")
+        // exe_dir.parent().unwrap().join("assets")
+    };
+
+    #[cfg(not(target_os = "linux"))]
+    let asset_dir = std::env::current_exe()?.parent().unwrap().join("assets");
+
+    Ok(asset_dir)
+}
+
 pub fn ensure_dir(path: PathBuf) -> PathBuf {
     if path.exists() {
         if !path.is_dir() {
@@ -0,0 +1,798 @@
+//! Hardware encoder detection. Test-encoding with every hardware encoder takes a few seconds, so
+//! the GUI process probes once, caches the result in `encoders.json` and hands it to every render
+//! child. The cache is keyed by the ffmpeg binary and a fingerprint of the installed GPU drivers.
+
+use crate::render::{cmd_hidden, find_ffmpeg, ErrorKind, RenderConfig};
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::Path,
+    process::{Command, Stdio},
+};
+use tracing::{info, warn};
+
+const CACHE_FILE: &str = "encoders.json";
+/// Bumped whenever the probe starts testing something new, so that old caches are not reused.
+const PROBE_VERSION: u32 = 2;
+
+/// Names of the encoders (and CUVID decoders) that were detected or passed their test.
+#[derive(Deserialize, Serialize, Clone, Default)]
+#[serde(transparent)]
+pub struct EncoderAvailability(BTreeSet<String>);
+
+impl EncoderAvailability {
+    pub fn contains(&self, name: &str) -> bool {
+        self.0.contains(name)
+    }
+
+    fn insert(&mut self, name: &str) {
+        self.0.insert(name.to_owned());
+    }
+
+    pub fn retain(&mut self, mut f: impl FnMut(&str) -> bool) {
+        self.0.retain(|it| f(it));
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &str> {
+        self.0.iter().map(String::as_str)
+    }
+}
+
+/// Who provides an encoder. Named after the values of `RenderConfig::encoder`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Vendor {
+    Cpu,
+    Nvenc,
+    Qsv,
+    Amf,
+    Vaapi,
+    Vulkan,
+}
+
+impl Vendor {
+    pub const HARDWARE: [Vendor; 5] = [Self::Nvenc, Self::Qsv, Self::Amf, Self::Vaapi, Self::Vulkan];
+
+    /// `None` for `auto` and anything unknown.
+    pub fn from_config(name: &str) -> Option<Self> {
+        Some(match name {
+            "cpu" => Self::Cpu,
+            "nvenc" => Self::Nvenc,
+            "qsv" => Self::Qsv,
+            "amf" => Self::Amf,
+            "vaapi" => Self::Vaapi,
+            "vulkan" => Self::Vulkan,
+            _ => return None,
+        })
+    }
+
+    /// The value of `RenderConfig::encoder` that prefers this vendor.
+    pub fn config_name(self) -> &'static str {
+        match self {
+            Self::Cpu => "cpu",
+            Self::Nvenc => "nvenc",
+            Self::Qsv => "qsv",
+            Self::Amf => "amf",
+            Self::Vaapi => "vaapi",
+            Self::Vulkan => "vulkan",
+        }
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            Self::Cpu => "CPU",
+            Self::Nvenc => "NVIDIA",
+            Self::Qsv => "Intel Quick Sync",
+            Self::Amf => "AMD AMF",
+            Self::Vaapi => "VAAPI",
+            Self::Vulkan => "Vulkan",
+        }
+    }
+
+    fn detect(self) -> bool {
+        match self {
+            Self::Cpu => true,
+            Self::Nvenc => hw_detect::detect_nvidia(),
+            Self::Qsv => hw_detect::detect_intel_qsv(),
+            Self::Amf => hw_detect::detect_amd(),
+            Self::Vaapi => hw_detect::detect_vaapi(),
+            Self::Vulkan => hw_detect::detect_vulkan(),
+        }
+    }
+}
+
+pub struct PresetSpec {
+    pub flag: &'static str,
+    pub source: PresetSource,
+}
+
+/// Which `RenderConfig` field the value of [`PresetSpec::flag`] comes from.
+pub enum PresetSource {
+    /// Word `index` of `RenderConfig::ffmpeg_preset`, which holds one whitespace-separated word
+    /// per vendor, e.g. `medium p4 balanced`.
+    Word { index: usize, default: &'static str },
+    SvtAv1Preset,
+    Rav1eSpeed,
+}
+
+impl PresetSpec {
+    pub fn value(&self, config: &RenderConfig) -> String {
+        match self.source {
+            PresetSource::Word { index, default } => config.ffmpeg_preset.split_whitespace().nth(index).unwrap_or(default).to_owned(),
+            PresetSource::SvtAv1Preset => config.svt_av1_preset.min(13).to_string(),
+            PresetSource::Rav1eSpeed => config.rav1e_speed.min(10).to_string(),
+        }
+    }
+}
+
+pub struct EncoderSpec {
+    /// ffmpeg encoder name.
+    pub name: &'static str,
+    /// `RenderConfig::video_codec` it produces.
+    pub codec: &'static str,
+    pub vendor: Vendor,
+    /// Flag taking the bitrate value in CRF mode.
+    pub quality_flag: &'static str,
+    pub preset: Option<PresetSpec>,
+    /// Applied to the RGBA input once it is flipped upright, also used by the probe.
+    pub filter: &'static str,
+    /// Arguments before the inputs that set up the device `filter` uploads to.
+    pub hw_device: &'static [&'static str],
+    pub two_pass: Option<TwoPass>,
+}
+
+/// How an encoder is told which pass it runs and where the statistics go.
+#[derive(Clone, Copy)]
+pub enum TwoPass {
+    /// ffmpeg's generic `-pass` and `-passlogfile`.
+    PassFlags,
+    /// libx265 ignores those and takes them through `-x265-params`.
+    X265Params,
+}
+
+impl EncoderSpec {
+    pub fn is_hardware(&self) -> bool {
+        self.vendor != Vendor::Cpu
+    }
+
+    /// Arguments for `pass` 1 or 2, `log` is the statistics file name prefix.
+    pub fn pass_args(&self, pass: u8, log: &str) -> Vec<String> {
+        match self.two_pass {
+            None => Vec::new(),
+            Some(TwoPass::PassFlags) => vec!["-pass".to_owned(), pass.to_string(), "-passlogfile".to_owned(), log.to_owned()],
+            Some(TwoPass::X265Params) => vec!["-x265-params".to_owned(), format!("pass={pass}:stats={log}.log")],
+        }
+    }
+
+    /// `filter` producing `bit_depth` bit frames. 10-bit frames are planar for software encoders
+    /// and P010 for hardware ones, which take it wherever they take NV12.
+    pub fn video_filter(&self, bit_depth: u32) -> String {
+        if bit_depth <= 8 {
+            return self.filter.to_owned();
+        }
+        let format = if self.is_hardware() { "p010le" } else { "yuv420p10le" };
+        self.filter.replacen("yuv420p", format, 1).replacen("nv12", format, 1)
+    }
+
+    pub fn hw_device_args(&self) -> Vec<String> {
+        self.hw_device.iter().map(|it| (*it).to_owned()).collect()
+    }
+
+    /// Test command run by the probe, see [`test_encoder`].
+    fn probe_args(&self) -> Vec<String> {
+        let mut args = self.hw_device_args();
+        let input = if !self.hw_device.is_empty() {
+            "testsrc=duration=0.1:size=320x240:rate=30"
+        } else {
+            "color=c=black:s=320x240:d=0"
+        };
+        args.extend(["-f", "lavfi", "-i", input, "-vf", self.filter, "-c:v", self.name, "-f", "null", "-"].map(str::to_owned));
+        args
+    }
+}
+
+const YUV420P: &str = "format=yuv420p";
+// Vulkan and VAAPI encoders only take NV12 frames in device memory
+const HW_NV12: &str = "format=nv12,hwupload";
+
+const NO_DEVICE: &[&str] = &[];
+const VULKAN_DEVICE: &[&str] = &["-init_hw_device", "vulkan=vk", "-filter_hw_device", "vk"];
+// Same device `hw_detect::detect_vaapi` checks
+const VAAPI_DEVICE: &[&str] = &["-vaapi_device", "/dev/dri/renderD128"];
+
+macro_rules! encoders {
+    ($($codec:literal, $vendor:ident => $name:literal, $quality:literal, $preset:expr, $filter:expr, $hw_device:expr, $two_pass:expr;)*) => {
+        &[$(EncoderSpec {
+            name: $name,
+            codec: $codec,
+            vendor: Vendor::$vendor,
+            quality_flag: $quality,
+            preset: $preset,
+            filter: $filter,
+            hw_device: $hw_device,
+            two_pass: $two_pass,
+        }),*]
+    };
+}
+
+const fn preset(flag: &'static str, index: usize, default: &'static str) -> Option<PresetSpec> {
+    Some(PresetSpec {
+        flag,
+        source: PresetSource::Word { index, default },
+    })
+}
+
+/// Every encoder we know how to drive. For each codec, hardware encoders are tried in this order
+/// (after the one the user prefers) and software encoders are the last resort, also in this
+/// order. Software encoders are only used if ffmpeg was built with them, except for the last one
+/// of each codec.
+pub const ENCODERS: &[EncoderSpec] = encoders![
+    "h264", Nvenc => "h264_nvenc", "-cq", preset("-preset", 1, "p4"), YUV420P, NO_DEVICE, None;
+    "h264", Qsv => "h264_qsv", "-q", preset("-preset", 0, "medium"), YUV420P, NO_DEVICE, None;
+    "h264", Amf => "h264_amf", "-qp_p", preset("-quality", 2, "balanced"), YUV420P, NO_DEVICE, None;
+    "h264", Vaapi => "h264_vaapi", "-qp", None, HW_NV12, VAAPI_DEVICE, None;
+    "h264", Vulkan => "h264_vulkan", "-qp", None, HW_NV12, VULKAN_DEVICE, None;
+    "h264", Cpu => "libx264", "-crf", preset("-preset", 0, "medium"), YUV420P, NO_DEVICE, Some(TwoPass::PassFlags);
+    "hevc", Nvenc => "hevc_nvenc", "-cq", preset("-preset", 1, "p4"), YUV420P, NO_DEVICE, None;
+    "hevc", Qsv => "hevc_qsv", "-q", preset("-preset", 0, "medium"), YUV420P, NO_DEVICE, None;
+    "hevc", Amf => "hevc_amf", "-qp_p", preset("-quality", 2, "balanced"), YUV420P, NO_DEVICE, None;
+    "hevc", Vaapi => "hevc_vaapi", "-qp", None, HW_NV12, VAAPI_DEVICE, None;
+    "hevc", Vulkan => "hevc_vulkan", "-qp", None, HW_NV12, VULKAN_DEVICE, None;
+    "hevc", Cpu => "libx265", "-crf", preset("-preset", 0, "medium"), YUV420P, NO_DEVICE, Some(TwoPass::X265Params);
+    "av1", Nvenc => "av1_nvenc", "-cq", preset("-preset", 1, "p4"), YUV420P, NO_DEVICE, None;
+    "av1", Qsv => "av1_qsv", "-q", preset("-preset", 0, "medium"), YUV420P, NO_DEVICE, None;
+    "av1", Amf => "av1_amf", "-qp_p", preset("-quality", 2, "balanced"), YUV420P, NO_DEVICE, None;
+    "av1", Vaapi => "av1_vaapi", "-global_quality", None, HW_NV12, VAAPI_DEVICE, None;
+    "av1", Vulkan => "av1_vulkan", "-qp", None, HW_NV12, VULKAN_DEVICE, None;
+    "av1", Cpu => "libsvtav1", "-crf", Some(PresetSpec { flag: "-preset", source: PresetSource::SvtAv1Preset }), YUV420P, NO_DEVICE, None;
+    "av1", Cpu => "librav1e", "-qp", Some(PresetSpec { flag: "-speed", source: PresetSource::Rav1eSpeed }), YUV420P, NO_DEVICE, None;
+    "av1", Cpu => "libaom-av1", "-crf", preset("-preset", 0, "medium"), YUV420P, NO_DEVICE, Some(TwoPass::PassFlags);
+];
+
+/// CUVID decoders with the software encoder and container used to produce their test input.
+const CUVID_DECODERS: [(&str, &str, &str); 3] = [
+    ("h264_cuvid", "libx264", "mpegts"),
+    ("hevc_cuvid", "libx265", "mpegts"),
+    ("av1_cuvid", "libaom-av1", "matroska"),
+];
+
+pub fn find_encoder(name: &str) -> Option<&'static EncoderSpec> {
+    ENCODERS.iter().find(|it| it.name == name)
+}
+
+/// Encoders for `codec` in the order they should be tried, given the user's `preference`
+/// (`RenderConfig::encoder`). Unknown codecs fall back to H.264.
+pub fn candidates(codec: &str, preference: &str) -> Vec<&'static EncoderSpec> {
+    let codec = if ENCODERS.iter().any(|it| it.codec == codec) { codec } else { "h264" };
+    let preferred = Vendor::from_config(preference);
+    let mut result: Vec<_> = ENCODERS
+        .iter()
+        .filter(|it| it.codec == codec && (preferred != Some(Vendor::Cpu) || !it.is_hardware()))
+        .collect();
+    // Stable, so the registry order is kept otherwise
+    result.sort_by_key(|it| (!it.is_hardware(), Some(it.vendor) != preferred));
+    result
+}
+
+#[cfg(target_os = "windows")]
+mod hw_detect {
+    use std::path::Path;
+    use winreg::{enums::HKEY_LOCAL_MACHINE, RegKey};
+
+    pub fn detect_nvidia() -> bool {
+        use std::process::Command;
+        use winreg::enums::HKEY_LOCAL_MACHINE;
+        use winreg::RegKey;
+        use std::path::Path;
+
+        if let Ok(key) = RegKey::predef(HKEY_LOCAL_MACHINE)
+            .open_subkey(r"SYSTEM\CurrentControlSet\Control\Class\{4d36e968-e325-11ce-bfc1-08002be10318}")
+        {
+            for subkey_name in key.enum_keys().filter_map(|x| x.ok()) {
+                if let Ok(subkey) = key.open_subkey(&subkey_name) {
+                    if let Ok(provider) = subkey.get_value::<String, _>("ProviderName") {
+                        if provider.to_lowercase().contains("nvidia") {
+                            return true;
+                        }
+                    }
+                }
+            }
+        }
+        if Path::new(r"C:\Windows\System32\nvcuda.dll").exists() {
+            return true;
+        }
+        Command::new("nvidia-smi").output().is_ok()
+    }
+
+    pub fn detect_intel_qsv() -> bool {
+        let mut found = false;
+        let classes = [
+            "{4d36e968-e325-11ce-bfc1-08002be10318}", // Display adapters
+            "{4d36e97d-e325-11ce-bfc1-08002be10318}", // System devices
+        ];
+
+        for class in classes {
+            if let Ok(key) = RegKey::predef(HKEY_LOCAL_MACHINE)
+                .open_subkey(format!(r"SYSTEM\CurrentControlSet\Control\Class\{}", class))
+            {
+                for subkey in key.enum_keys().filter_map(|x| x.ok()) {
+                    if let Ok(subkey) = key.open_subkey(subkey) {
+                        if let Ok(provider) = subkey.get_value::<String, _>("ProviderName") {
+                            if provider.contains("Intel") {
+                                found = true;
+                                break;
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        found
+    }
+
+    pub fn detect_amd() -> bool {
+        Path::new(r"C:\Windows\System32\amdvlk64.dll").exists()
+            || Path::new(r"C:\Windows\System32\amfrt64.dll").exists()
+    }
+
+    pub fn detect_vulkan() -> bool {
+        // Check for Vulkan runtime (Vulkan Loader)
+        Path::new(r"C:\Windows\System32\vulkan-1.dll").exists()
+    }
+
+    pub fn detect_vaapi() -> bool {
+        // VA-API only exists on Linux
+        false
+    }
+
+    /// Description and version of every display adapter driver.
+    pub fn driver_fingerprint() -> String {
+        let mut result = String::new();
+        if let Ok(key) = RegKey::predef(HKEY_LOCAL_MACHINE)
+            .open_subkey(r"SYSTEM\CurrentControlSet\Control\Class\{4d36e968-e325-11ce-bfc1-08002be10318}")
+        {
+            for subkey in key.enum_keys().filter_map(|x| x.ok()) {
+                if let Ok(subkey) = key.open_subkey(subkey) {
+                    let desc = subkey.get_value::<String, _>("DriverDesc").unwrap_or_default();
+                    let version = subkey.get_value::<String, _>("DriverVersion").unwrap_or_default();
+                    result.push_str(&format!("{desc} {version};"));
+                }
+            }
+        }
+        result
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod hw_detect {
+    use std::path::Path;
+    use std::process::Command;
+    use std::time::UNIX_EPOCH;
+
+    pub fn detect_nvidia() -> bool {
+        Path::new("/dev/nvidia0").exists() || Command::new("nvidia-smi").status().is_ok()
+    }
+
+    pub fn detect_intel_qsv() -> bool {
+        Path::new("/dev/dri/renderD128").exists()
+            && Command::new("vainfo")
+            .output()
+            .map(|out| String::from_utf8_lossy(&out.stdout).contains("VAProfileH264"))
+            .unwrap_or(false)
+    }
+
+    pub fn detect_amd() -> bool {
+        Path::new("/dev/kfd").exists()
+            && Command::new("vainfo")
+            .output()
+            .map(|out| String::from_utf8_lossy(&out.stdout).contains("AMD"))
+            .unwrap_or(false)
+    }
+
+    /// Any VA-API driver that can encode, i.e. Mesa (AMD, Intel through Iris) or intel-media-driver.
+    pub fn detect_vaapi() -> bool {
+        Path::new("/dev/dri/renderD128").exists()
+            && Command::new("vainfo")
+            .output()
+            .map(|out| String::from_utf8_lossy(&out.stdout).contains("VAEntrypointEncSlice"))
+            .unwrap_or(false)
+    }
+
+    pub fn detect_vulkan() -> bool {
+        // Check for Vulkan ICD files (indicates Vulkan driver is installed)
+        Path::new("/usr/share/vulkan/icd.d").exists()
+            || Path::new("/etc/vulkan/icd.d").exists()
+            || Path::new("/usr/local/share/vulkan/icd.d").exists()
+    }
+
+    /// Kernel and NVIDIA/AMD kernel driver versions, plus the Vulkan ICD manifests. Mesa doesn't
+    /// expose its version without loading it, but package updates rewrite the manifests.
+    pub fn driver_fingerprint() -> String {
+        let mut result = String::new();
+        for file in ["/proc/sys/kernel/osrelease", "/proc/driver/nvidia/version", "/sys/module/amdgpu/version"] {
+            if let Ok(content) = std::fs::read_to_string(file) {
+                result.push_str(content.trim());
+                result.push(';');
+            }
+        }
+        for dir in ["/usr/share/vulkan/icd.d", "/etc/vulkan/icd.d", "/usr/local/share/vulkan/icd.d"] {
+            let Ok(entries) = std::fs::read_dir(dir) else {
+                continue;
+            };
+            let mut manifests: Vec<_> = entries
+                .filter_map(|entry| {
+                    let entry = entry.ok()?;
+                    let modified = entry.metadata().ok()?.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+                    Some(format!("{}@{};", entry.file_name().to_string_lossy(), modified.as_secs()))
+                })
+                .collect();
+            manifests.sort();
+            result.extend(manifests);
+        }
+        result
+    }
+}
+
+#[cfg(target_os = "macos")]
+mod hw_detect {
+    use std::process::Command;
+
+    pub fn detect_nvidia() -> bool {
+        Command::new("system_profiler")
+            .args(&["SPDisplaysDataType"])
+            .output()
+            .map(|out| String::from_utf8_lossy(&out.stdout).contains("NVIDIA"))
+            .unwrap_or(false)
+    }
+
+    pub fn detect_intel_qsv() -> bool {
+        Command::new("system_profiler")
+            .args(&["SPDisplaysDataType"])
+            .output()
+            .map(|out| String::from_utf8_lossy(&out.stdout).contains("Intel"))
+            .unwrap_or(false)
+    }
+
+    pub fn detect_amd() -> bool {
+        Command::new("system_profiler")
+            .args(&["SPDisplaysDataType"])
+            .output()
+            .map(|out| String::from_utf8_lossy(&out.stdout).contains("AMD"))
+            .unwrap_or(false)
+    }
+
+    pub fn detect_vaapi() -> bool {
+        // VA-API only exists on Linux
+        false
+    }
+
+    pub fn detect_vulkan() -> bool {
+        // macOS uses MoltenVK for Vulkan support
+        // Check for MoltenVK or Vulkan loader
+        Command::new("sh")
+            .arg("-c")
+            .arg("ls /usr/local/lib/libMoltenVK.dylib 2>/dev/null || ls /opt/homebrew/lib/libMoltenVK.dylib 2>/dev/null || ls ~/Library/Frameworks/libMoltenVK.dylib 2>/dev/null")
+            .status()
+            .map(|s| s.success())
+            .unwrap_or(false)
+    }
+
+    /// GPU drivers ship with macOS, so the OS version identifies them.
+    pub fn driver_fingerprint() -> String {
+        std::fs::read_to_string("/System/Library/CoreServices/SystemVersion.plist").unwrap_or_default()
+    }
+}
+
+fn test_encoder(ffmpeg: &str, encoder: &EncoderSpec) -> Result<(bool, String)> {
+    let output = Command::new(ffmpeg)
+        .args(encoder.probe_args())
+        .arg("-loglevel")
+        .arg("warning")
+        .arg("-hide_banner")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .output()
+        .with_context(|| format!("Failed to start encoder test for {}", encoder.name))?;
+
+    let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+    Ok((output.status.success(), stderr))
+}
+
+/// Identifies what a probe was made with. A cached probe is reused only while this stays the same.
+#[derive(Deserialize, Serialize, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct ProbeKey {
+    /// [`PROBE_VERSION`] when the probe was made.
+    pub version: u32,
+    pub ffmpeg: String,
+    /// First line of `ffmpeg -version`.
+    pub ffmpeg_version: String,
+    pub driver_fingerprint: String,
+}
+
+impl ProbeKey {
+    pub fn current(ffmpeg: &str) -> Result<Self> {
+        let output = cmd_hidden(ffmpeg)
+            .arg("-version")
+            .stdin(Stdio::null())
+            .stderr(Stdio::null())
+            .output()
+            .with_context(|| format!("failed to run {ffmpeg}"))?;
+        Ok(Self {
+            version: PROBE_VERSION,
+            ffmpeg: ffmpeg.to_owned(),
+            ffmpeg_version: String::from_utf8_lossy(&output.stdout).lines().next().unwrap_or_default().to_owned(),
+            driver_fingerprint: hw_detect::driver_fingerprint(),
+        })
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct EncoderProbe {
+    pub key: ProbeKey,
+    /// Encoders whose hardware was found, whether or not the test encode succeeded.
+    pub detected: EncoderAvailability,
+    pub available: EncoderAvailability,
+    /// Why the test failed, by encoder name.
+    pub errors: BTreeMap<String, String>,
+}
+
+impl EncoderProbe {
+    /// Returns the probe cached in `config_dir` if it still matches `ffmpeg` and the installed
+    /// drivers, probing again and updating the cache otherwise.
+    pub fn cached(ffmpeg: &str, config_dir: Option<&Path>, force: bool) -> Result<Self> {
+        let key = ProbeKey::current(ffmpeg)?;
+        let file = config_dir.map(|dir| dir.join(CACHE_FILE));
+        if !force {
+            if let Some(cached) = file.as_deref().and_then(Self::load).filter(|it| it.key == key) {
+                return Ok(cached);
+            }
+        }
+
+        info!("Probing encoders of {ffmpeg}");
+        let probe = Self::probe(key);
+        if let Some(file) = file {
+            if let Err(err) = probe.save(&file) {
+                warn!("Failed to save encoder probe: {err:?}");
+            }
+        }
+        Ok(probe)
+    }
+
+    fn load(file: &Path) -> Option<Self> {
+        serde_json::from_slice(&std::fs::read(file).ok()?).ok()
+    }
+
+    fn save(&self, file: &Path) -> Result<()> {
+        // Render children may read the cache at the same time
+        let tmp = file.with_extension("json.tmp");
+        std::fs::write(&tmp, serde_json::to_vec(self)?)?;
+        std::fs::rename(&tmp, file)?;
+        Ok(())
+    }
+
+    fn probe(key: ProbeKey) -> Self {
+        let ffmpeg = key.ffmpeg.as_str();
+        let vendors: Vec<_> = Vendor::HARDWARE.into_iter().filter(|vendor| vendor.detect()).collect();
+        let mut detected = EncoderAvailability::default();
+        let mut available = EncoderAvailability::default();
+        let mut errors = BTreeMap::new();
+
+        for encoder in ENCODERS.iter().filter(|it| vendors.contains(&it.vendor)) {
+            let name = encoder.name;
+            detected.insert(name);
+            match test_encoder(ffmpeg, encoder) {
+                Ok((success, error_output)) => {
+                    if success {
+                        available.insert(name);
+                    } else {
+                        errors.insert(name.to_owned(), format!(
+                            "{} test failed:\n{}",
+                            name,
+                            error_output.trim()
+                        ));
+                    }
+                }
+                Err(e) => {
+                    errors.insert(name.to_owned(), format!("{} test error: {}", name, e));
+                }
+            }
+        }
+
+        // Software encoders only have to be compiled in
+        match ffmpeg_table(ffmpeg, "-encoders") {
+            Ok(lines) => {
+                for encoder in ENCODERS.iter().filter(|it| !it.is_hardware()) {
+                    // e.g. ` V....D libx264  libx264 H.264 / AVC / MPEG-4 AVC`
+                    if lines.iter().any(|line| line.split_whitespace().nth(1) == Some(encoder.name)) {
+                        detected.insert(encoder.name);
+                        available.insert(encoder.name);
+                    }
+                }
+            }
+            Err(err) => warn!("Failed to list encoders: {err:?}"),
+        }
+
+        if vendors.contains(&Vendor::Nvenc) {
+            for (name, encoder_name, container_format) in CUVID_DECODERS {
+                detected.insert(name);
+
+                let mut encode_cmd = Command::new(ffmpeg);
+                encode_cmd.args(&[
+                    "-f", "lavfi",
+                    "-i", "testsrc=duration=1:size=320x240:rate=30",
+                    "-vf", "format=yuv420p",  // 强制使用 yuv420p 格式
+                    "-c:v", encoder_name,
+                    "-t", "0.5",  // 只编码0.5秒
+                    "-f", container_format,
+                    "-"  // 输出到stdout
+                ])
+                    .stdout(Stdio::piped())
+                    .stderr(Stdio::null());
+
+                // 2. 解码测试
+                let mut decode_cmd = Command::new(ffmpeg);
+                decode_cmd.args(&[
+                    "-hwaccel", "cuvid",
+                    "-hwaccel_device", "0",
+                    "-c:v", name,
+                    "-f", container_format,
+                    "-i", "-",  // 从stdin读取
+                    "-f", "null",
+                    "-"  // 输出到null
+                ])
+                    .stdin(Stdio::piped())
+                    .stdout(Stdio::null())
+                    .stderr(Stdio::piped());
+
+                let encoded = match encode_cmd.spawn() {
+                    Ok(child) => child,
+                    Err(e) => {
+                        errors.insert(name.to_owned(), format!("{} test encode setup failed: {}", name, e));
+                        continue;
+                    }
+                };
+
+                decode_cmd.stdin(encoded.stdout.unwrap());
+
+                match decode_cmd.output() {
+                    Ok(output) => {
+                        if output.status.success() {
+                            available.insert(name);
+                        } else {
+                            let stderr = String::from_utf8_lossy(&output.stderr);
+                            errors.insert(name.to_owned(), format!(
+                                "{} decode test failed (code {}):\n{}",
+                                name,
+                                output.status.code().unwrap_or(-1),
+                                stderr.trim()
+                            ));
+                        }
+                    }
+                    Err(e) => {
+                        errors.insert(name.to_owned(), format!("{} decode test execution failed: {}", name, e));
+                    }
+                }
+            }
+        }
+
+        Self {
+            key,
+            detected,
+            available,
+            errors,
+        }
+    }
+}
+
+/// Probe shared by the tasks of the GUI process, cached under `CONFIG_DIR`.
+pub async fn shared_probe(force: bool) -> Result<EncoderProbe> {
+    // Concurrently starting tasks would otherwise all probe at once
+    static LOCK: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+    let _guard = LOCK.lock().await;
+    let Some(ffmpeg) = find_ffmpeg()? else {
+        bail!(ErrorKind::FfmpegMissing)
+    };
+    let config_dir = crate::common::CONFIG_DIR.get().cloned();
+    tokio::task::spawn_blocking(move || EncoderProbe::cached(&ffmpeg, config_dir.as_deref(), force)).await?
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EncoderReport {
+    pub name: &'static str,
+    pub detected: bool,
+    pub available: bool,
+    /// Output of the failed test, if it was run and failed.
+    pub error: Option<String>,
+}
+
+/// Everything we know about the encoding capabilities of this machine, for diagnosing it without
+/// going through render logs.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CapabilityReport {
+    pub ffmpeg: String,
+    pub ffmpeg_version: String,
+    /// Whether each hardware vendor was detected, by [`Vendor::label`].
+    pub vendors: BTreeMap<&'static str, bool>,
+    pub encoders: Vec<EncoderReport>,
+    /// Pixel formats ffmpeg can convert to.
+    pub pixel_formats: Vec<String>,
+    pub audio_encoders: Vec<String>,
+}
+
+/// Runs `ffmpeg -hide_banner <arg>` and returns the lines of the table below the `---` separator.
+fn ffmpeg_table(ffmpeg: &str, arg: &str) -> Result<Vec<String>> {
+    let output = cmd_hidden(ffmpeg)
+        .args(["-hide_banner", arg])
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+        .with_context(|| format!("failed to run {ffmpeg} {arg}"))?;
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .skip_while(|line| !line.trim_start().starts_with("---"))
+        .skip(1)
+        .map(str::to_owned)
+        .collect())
+}
+
+impl CapabilityReport {
+    pub fn new(probe: EncoderProbe) -> Result<Self> {
+        let ffmpeg = probe.key.ffmpeg.as_str();
+        // Flags come first, e.g. `IO... yuv420p  3  12  8-8-8`, `O` means it can be an output
+        let pixel_formats = ffmpeg_table(ffmpeg, "-pix_fmts")?
+            .iter()
+            .filter_map(|line| {
+                let mut fields = line.split_whitespace();
+                let flags = fields.next()?;
+                (flags.as_bytes().get(1) == Some(&b'O')).then(|| fields.next()).flatten().map(str::to_owned)
+            })
+            .collect();
+        // e.g. ` A....D aac  AAC (Advanced Audio Coding)`
+        let audio_encoders = ffmpeg_table(ffmpeg, "-encoders")?
+            .iter()
+            .filter_map(|line| {
+                let mut fields = line.split_whitespace();
+                let flags = fields.next()?;
+                flags.starts_with('A').then(|| fields.next()).flatten().map(str::to_owned)
+            })
+            .collect();
+
+        let hardware = ENCODERS.iter().filter(|it| it.is_hardware());
+        let vendors = Vendor::HARDWARE
+            .into_iter()
+            .map(|vendor| {
+                let detected = hardware.clone().any(|it| it.vendor == vendor && probe.detected.contains(it.name));
+                (vendor.label(), detected)
+            })
+            .collect();
+        let encoders = ENCODERS
+            .iter()
+            .map(|it| it.name)
+            .chain(CUVID_DECODERS.map(|(name, ..)| name))
+            .map(|name| EncoderReport {
+                name,
+                detected: probe.detected.contains(name),
+                available: probe.available.contains(name),
+                error: probe.errors.get(name).cloned(),
+            })
+            .collect();
+
+        Ok(Self {
+            ffmpeg: probe.key.ffmpeg.clone(),
+            ffmpeg_version: probe.key.ffmpeg_version.clone(),
+            vendors,
+            encoders,
+            pixel_formats,
+            audio_encoders,
+        })
+    }
+}
+
+pub async fn capability_report() -> Result<CapabilityReport> {
+    let probe = shared_probe(false).await?;
+    tokio::task::spawn_blocking(move || CapabilityReport::new(probe)).await?
+}
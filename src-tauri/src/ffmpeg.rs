@@ -0,0 +1,383 @@
+//! Typed ffmpeg invocations. Every value is passed as its own argument, so paths and settings
+//! containing spaces reach ffmpeg unchanged, and the arguments can be inspected without running
+//! ffmpeg.
+
+use crate::render::cmd_hidden;
+use anyhow::{bail, Result};
+use std::{ffi::OsString, path::PathBuf, process::Command};
+
+/// One `-i` input with the options that apply to it.
+#[derive(Clone)]
+pub struct Input {
+    options: Vec<OsString>,
+    source: OsString,
+}
+
+impl Input {
+    pub fn new(source: impl Into<OsString>) -> Self {
+        Self {
+            options: Vec::new(),
+            source: source.into(),
+        }
+    }
+
+    /// Raw frames of `pix_fmt` written to stdin.
+    pub fn raw_video(width: u32, height: u32, fps: u32, pix_fmt: &str) -> Self {
+        Self::new("-")
+            .format("rawvideo")
+            .option("-c:v", "rawvideo")
+            .option("-s", format!("{width}x{height}"))
+            .option("-r", fps.to_string())
+            .option("-pix_fmt", pix_fmt)
+    }
+
+    /// Interleaved 32-bit float stereo samples written to stdin.
+    pub fn raw_audio(sample_rate: u32) -> Self {
+        Self::new("-")
+            .format("f32le")
+            .option("-ar", sample_rate.to_string())
+            .option("-ac", "2")
+    }
+
+    pub fn format(self, format: &str) -> Self {
+        self.option("-f", format)
+    }
+
+    pub fn option(mut self, flag: impl Into<OsString>, value: impl Into<OsString>) -> Self {
+        self.options.push(flag.into());
+        self.options.push(value.into());
+        self
+    }
+
+    pub fn args<I, S>(mut self, args: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<OsString>,
+    {
+        self.options.extend(args.into_iter().map(Into::into));
+        self
+    }
+}
+
+/// Options of one output: stream selection, codecs, filters and metadata. The file it writes to
+/// is given to [`FfmpegCommand::output`], so the same options can be reused for several files.
+#[derive(Clone, Default)]
+pub struct Output {
+    options: Vec<OsString>,
+}
+
+impl Output {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Selects streams by `spec`, like `0:v:0`.
+    pub fn map(self, spec: &str) -> Self {
+        self.option("-map", spec)
+    }
+
+    /// Sets the codec of the `stream` type, `v` or `a`.
+    pub fn codec(self, stream: &str, codec: &str) -> Self {
+        self.option(format!("-c:{stream}"), codec)
+    }
+
+    /// Sets the simple filter graph of the `stream` type, `v` or `a`.
+    pub fn filter(self, stream: &str, filter: impl Into<OsString>) -> Self {
+        self.option(format!("-filter:{stream}"), filter)
+    }
+
+    pub fn no_audio(self) -> Self {
+        self.arg("-an")
+    }
+
+    /// Drops everything before `seconds`.
+    pub fn seek(self, seconds: f64) -> Self {
+        self.option("-ss", seconds.to_string())
+    }
+
+    pub fn format(self, format: &str) -> Self {
+        self.option("-f", format)
+    }
+
+    pub fn option(mut self, flag: impl Into<OsString>, value: impl Into<OsString>) -> Self {
+        self.options.push(flag.into());
+        self.options.push(value.into());
+        self
+    }
+
+    pub fn arg(mut self, arg: impl Into<OsString>) -> Self {
+        self.options.push(arg.into());
+        self
+    }
+
+    /// Arguments that are already split, like an encoder's rate control or pass options.
+    pub fn args<I, S>(mut self, args: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<OsString>,
+    {
+        self.options.extend(args.into_iter().map(Into::into));
+        self
+    }
+}
+
+/// A whole ffmpeg command line, with global options, inputs and outputs kept in the order
+/// ffmpeg expects them.
+#[derive(Clone)]
+pub struct FfmpegCommand {
+    global: Vec<OsString>,
+    log_level: &'static str,
+    inputs: Vec<Input>,
+    outputs: Vec<(Output, OsString)>,
+    current_dir: Option<PathBuf>,
+}
+
+impl FfmpegCommand {
+    /// Overwrites existing outputs and only logs errors.
+    pub fn new() -> Self {
+        Self {
+            global: vec!["-y".into()],
+            log_level: "error",
+            inputs: Vec::new(),
+            outputs: Vec::new(),
+            current_dir: None,
+        }
+    }
+
+    /// Options before the inputs, like hardware device setup.
+    pub fn global<I, S>(mut self, args: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<OsString>,
+    {
+        self.global.extend(args.into_iter().map(Into::into));
+        self
+    }
+
+    pub fn log_level(mut self, level: &'static str) -> Self {
+        self.log_level = level;
+        self
+    }
+
+    pub fn input(mut self, input: Input) -> Self {
+        self.inputs.push(input);
+        self
+    }
+
+    /// Adds an output writing to `target`, `-` for stdout.
+    pub fn output(mut self, output: Output, target: impl Into<OsString>) -> Self {
+        self.outputs.push((output, target.into()));
+        self
+    }
+
+    /// Runs ffmpeg in `dir`, for relative paths in option values that can't take absolute ones.
+    pub fn current_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.current_dir = Some(dir.into());
+        self
+    }
+
+    pub fn build(&self) -> Vec<OsString> {
+        let mut args = self.global.clone();
+        args.extend(["-loglevel".into(), self.log_level.into()]);
+        for input in &self.inputs {
+            args.extend(input.options.iter().cloned());
+            args.extend(["-i".into(), input.source.clone()]);
+        }
+        for (output, target) in &self.outputs {
+            args.extend(output.options.iter().cloned());
+            args.push(target.clone());
+        }
+        args
+    }
+
+    /// A hidden `ffmpeg` process running this command line.
+    pub fn command(&self, ffmpeg: &str) -> Command {
+        let mut cmd = cmd_hidden(ffmpeg);
+        cmd.args(self.build());
+        if let Some(dir) = &self.current_dir {
+            cmd.current_dir(dir);
+        }
+        cmd
+    }
+}
+
+impl Default for FfmpegCommand {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Flags the pipeline sets itself.
+const RESERVED_FLAGS: &[&str] = &["-i", "-f", "-y", "-n"];
+
+/// Flag families the pipeline sets itself, reserved together with their stream specifiers and
+/// variants, e.g. `-filter:v`, `-filter_complex`, `-c:v` or `-pass:v`. Filters go through
+/// `RenderConfig::extra_video_filters`, `-pass` and `-passlogfile` belong to two-pass encodes.
+const RESERVED_PREFIXES: &[&str] = &[
+    "-map", "-filter", "-vf", "-af", "-lavfi", "-c", "-codec", "-pix_fmt", "-ss", "-an", "-s", "-r", "-pass", "-passlogfile",
+];
+
+fn is_reserved(arg: &str) -> bool {
+    // ffmpeg reads `-/flag` as `-flag` with its value loaded from a file
+    let flag = match arg.strip_prefix("-/") {
+        Some(name) => format!("-{name}"),
+        None => arg.to_owned(),
+    };
+    RESERVED_FLAGS.contains(&flag.as_str())
+        || RESERVED_PREFIXES.iter().any(|prefix| {
+            flag.strip_prefix(prefix)
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with([':', '_']))
+        })
+}
+
+/// Splits user-supplied arguments at whitespace outside of single or double quotes, rejecting
+/// reserved flags. Backslashes are kept as they are for Windows paths.
+pub fn user_args(args: &str) -> Result<Vec<String>> {
+    let mut result = Vec::new();
+    let mut current: Option<String> = None;
+    let mut quote = None;
+    for c in args.chars() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), c) => current.get_or_insert_with(String::new).push(c),
+            (None, '"' | '\'') => {
+                quote = Some(c);
+                current.get_or_insert_with(String::new);
+            }
+            (None, c) if c.is_whitespace() => result.extend(current.take()),
+            (None, c) => current.get_or_insert_with(String::new).push(c),
+        }
+    }
+    if let Some(q) = quote {
+        bail!("unclosed {q} in `{args}`");
+    }
+    result.extend(current);
+    if let Some(flag) = result.iter().find(|it| is_reserved(it)) {
+        bail!("`{flag}` is set by Phi-TK and can't be passed as an extra argument");
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    fn build(command: &FfmpegCommand) -> Vec<String> {
+        command.build().into_iter().map(|it| it.into_string().unwrap()).collect()
+    }
+
+    #[test]
+    fn global_options_come_before_inputs_and_outputs() {
+        let command = FfmpegCommand::new()
+            .global(["-hwaccel", "cuda"])
+            .log_level("warning")
+            .input(Input::raw_audio(48000))
+            .input(Input::new("music.ogg").option("-ss", "1"))
+            .output(Output::new().map("1:a:0").codec("a", "aac"), "out.m4a")
+            .output(Output::new().no_audio().format("null"), "-");
+        assert_eq!(
+            build(&command),
+            [
+                "-y", "-hwaccel", "cuda", "-loglevel", "warning",
+                "-f", "f32le", "-ar", "48000", "-ac", "2", "-i", "-",
+                "-ss", "1", "-i", "music.ogg",
+                "-map", "1:a:0", "-c:a", "aac", "out.m4a",
+                "-an", "-f", "null", "-",
+            ]
+        );
+    }
+
+    #[test]
+    fn values_with_spaces_stay_one_argument() {
+        let command = FfmpegCommand::new()
+            .input(Input::new("My Charts/song 1.ogg"))
+            .output(Output::new().filter("v", "scale=1280:720, vflip"), r"C:\My Videos\out 1.mp4");
+        assert_eq!(
+            build(&command),
+            [
+                "-y", "-loglevel", "error",
+                "-i", "My Charts/song 1.ogg",
+                "-filter:v", "scale=1280:720, vflip", r"C:\My Videos\out 1.mp4",
+            ]
+        );
+    }
+
+    #[test]
+    fn current_dir_is_not_an_argument() {
+        let command = FfmpegCommand::new().output(Output::new(), "out.mp4");
+        assert_eq!(command.command("ffmpeg").get_current_dir(), None);
+        let command = command.current_dir("passes");
+        assert_eq!(command.command("ffmpeg").get_current_dir(), Some(Path::new("passes")));
+        assert_eq!(build(&command), ["-y", "-loglevel", "error", "out.mp4"]);
+    }
+
+    #[test]
+    fn user_args_split_outside_quotes() {
+        assert_eq!(
+            user_args(r#" -metadata title="My Song"  -x265-params 'aq-mode=3:psy-rd=1' "#).unwrap(),
+            ["-metadata", "title=My Song", "-x265-params", "aq-mode=3:psy-rd=1"]
+        );
+        assert_eq!(user_args(r#"-metadata comment="" -tag:v hvc1"#).unwrap(), ["-metadata", "comment=", "-tag:v", "hvc1"]);
+        assert_eq!(user_args(r#"-metadata "" "it's""#).unwrap(), ["-metadata", "", "it's"]);
+        assert_eq!(user_args(r"-attach C:\Fonts\a.ttf").unwrap(), ["-attach", r"C:\Fonts\a.ttf"]);
+        assert!(user_args("   ").unwrap().is_empty());
+    }
+
+    #[test]
+    fn user_args_reject_unclosed_quotes() {
+        assert!(user_args(r#"-metadata title="My Song"#).is_err());
+        assert!(user_args("-metadata 'title").is_err());
+    }
+
+    #[test]
+    fn user_args_reject_reserved_flags() {
+        for args in ["-i extra.mp4", "-map 0:v", "-f matroska", "-y", "-vf scale=1:1", "-filter_complex [0]null"] {
+            assert!(user_args(args).is_err(), "{args}");
+        }
+    }
+
+    #[test]
+    fn user_args_reject_reserved_flag_variants() {
+        for args in [
+            "-filter:v:0 scale=1:1",
+            "-filter_complex_script graph.txt",
+            "-vf:0 scale=1:1",
+            "-af volume=2",
+            "-lavfi [0]null",
+            "-map_metadata -1",
+            "-/vf graph.txt",
+            "-/filter:v graph.txt",
+            "-pass 1",
+            "-pass:v 1",
+            "-passlogfile:v stats",
+            "-c:v libx264",
+            "-codec:a aac",
+            "-pix_fmt:v yuv444p",
+            "-ss 10",
+            "-an",
+            "-s 1280x720",
+            "-r:v 30",
+        ] {
+            assert!(user_args(args).is_err(), "{args}");
+        }
+    }
+
+    #[test]
+    fn user_args_keep_flags_sharing_a_prefix() {
+        for args in [
+            "-flags +cgop",
+            "-fps_mode cfr",
+            "-vframes 10",
+            "-avoid_negative_ts make_zero",
+            "-movflags +faststart",
+            "-crf 18",
+            "-sws_flags lanczos",
+            "-shortest",
+            "-rc vbr",
+            "-ar 44100",
+        ] {
+            assert!(user_args(args).is_ok(), "{args}");
+        }
+    }
+}
@@ -0,0 +1,258 @@
+//! Which clip each note plays in the mix. The built-in clicks can be replaced by a preset folder
+//! or the resource pack, and RPE charts can give single notes their own sound. Holds can also
+//! sustain and release if the folder or pack has `hold_loop` and `hold_release` clips, which the
+//! built-in assets don't.
+
+use crate::{mixer::Track, render::RenderConfig};
+use anyhow::{Context, Result};
+use macroquad::prelude::{load_file, warn};
+use prpr::{
+    core::{Note, NoteKind},
+    fs::{self, FileSystem},
+    info::ChartInfo,
+};
+use sasa::AudioClip;
+use serde::Deserialize;
+use std::{collections::HashMap, path::Path};
+
+const EXTENSIONS: [&str; 3] = ["ogg", "wav", "mp3"];
+
+pub struct Hitsounds {
+    click: Track,
+    drag: Track,
+    flick: Track,
+    /// Looped while a hold is held.
+    hold_loop: Option<Track>,
+    /// Played at the end of a hold.
+    hold_release: Option<Track>,
+    /// Volume multipliers indexed by [`NoteKind::order`].
+    volumes: [f32; 4],
+    /// Custom hitsounds of RPE charts, each loaded once.
+    clips: Vec<Track>,
+    /// Notes at the same line index, time in milliseconds and [`NoteKind::order`] where at least
+    /// one has a custom hitsound, with the index of its clip. Notes sharing a key are matched in
+    /// chart order, the second item is the next one to play.
+    custom: HashMap<NoteKey, (Vec<Option<usize>>, usize)>,
+}
+
+type NoteKey = (usize, i64, u8);
+
+impl Hitsounds {
+    pub async fn load(config: &RenderConfig, chart_fs: &mut dyn FileSystem, info: &ChartInfo, rate: u32) -> Result<Self> {
+        let mut pack: Option<Box<dyn FileSystem>> = match &config.res_pack_path {
+            Some(path) => match fs::fs_from_file(Path::new(path)) {
+                Ok(fs) => Some(fs),
+                Err(err) => {
+                    warn!("Failed to open resource pack {}: {:?}", path, err);
+                    None
+                }
+            },
+            None => None,
+        };
+        let dir = config.hitsound_dir.as_deref().filter(|it| !it.is_empty()).map(Path::new);
+        let click = load_clip("click", dir, &mut pack, rate).await?;
+        let drag = load_clip("drag", dir, &mut pack, rate).await?;
+        let flick = load_clip("flick", dir, &mut pack, rate).await?;
+        let (hold_loop, hold_release) = if config.hold_sounds {
+            (
+                find_clip("hold_loop", dir, &mut pack, rate).await?,
+                find_clip("hold_release", dir, &mut pack, rate).await?,
+            )
+        } else {
+            (None, None)
+        };
+
+        let mut clips = Vec::new();
+        let mut indices: HashMap<String, Option<usize>> = HashMap::new();
+        let mut custom: HashMap<NoteKey, (Vec<Option<usize>>, usize)> = HashMap::new();
+        for (key, path) in custom_hitsounds(chart_fs, info).await {
+            let index = match path {
+                Some(path) => {
+                    if !indices.contains_key(&path) {
+                        let index = match chart_fs.load_file(&path).await.and_then(AudioClip::new) {
+                            Ok(clip) => {
+                                clips.push(Track::of(&clip, rate));
+                                Some(clips.len() - 1)
+                            }
+                            Err(err) => {
+                                warn!("Failed to load custom hitsound {}: {:?}", path, err);
+                                None
+                            }
+                        };
+                        indices.insert(path.clone(), index);
+                    }
+                    indices[&path]
+                }
+                None => None,
+            };
+            custom.entry(key).or_default().0.push(index);
+        }
+        custom.retain(|_, (notes, _)| notes.iter().any(Option::is_some));
+
+        Ok(Self {
+            click,
+            drag,
+            flick,
+            hold_loop,
+            hold_release,
+            volumes: [config.volume_click, config.volume_drag, config.volume_hold, config.volume_flick],
+            clips,
+            custom,
+        })
+    }
+
+    /// Clip and volume multiplier of `note` on line `line`. Has to be called once for every note
+    /// that is played, in chart order.
+    pub fn of(&mut self, line: usize, note: &Note) -> (&Track, f32) {
+        let kind = note.kind.order();
+        let volume = self.volumes[kind as usize];
+        let time = (note.time as f64 * 1000.).round() as i64;
+        // Times are rounded separately on both sides and may end up a millisecond apart
+        let notes = [time, time - 1, time + 1]
+            .into_iter()
+            .find_map(|time| self.custom.get_mut(&(line, time, kind)).filter(|(notes, next)| *next < notes.len()));
+        if let Some((notes, next)) = notes {
+            *next += 1;
+            if let Some(index) = notes[*next - 1] {
+                return (&self.clips[index], volume);
+            }
+        }
+        let clip = match note.kind {
+            NoteKind::Drag => &self.drag,
+            NoteKind::Flick => &self.flick,
+            _ => &self.click,
+        };
+        (clip, volume)
+    }
+
+    /// Sustain of a hold lasting `duration` seconds, if there is a loop clip.
+    pub fn sustain(&self, duration: f64) -> Option<Track> {
+        self.hold_loop.as_ref().map(|it| it.looped(duration))
+    }
+
+    pub fn release(&self) -> Option<&Track> {
+        self.hold_release.as_ref()
+    }
+}
+
+/// Data of hitsound `name` in `dir` or the resource pack, whichever has it first.
+async fn find_data(name: &str, dir: Option<&Path>, pack: &mut Option<Box<dyn FileSystem>>) -> Option<Vec<u8>> {
+    if let Some(dir) = dir {
+        for extension in EXTENSIONS {
+            if let Ok(data) = std::fs::read(dir.join(format!("{name}.{extension}"))) {
+                return Some(data);
+            }
+        }
+    }
+    pack.as_mut()?.load_file(&format!("{name}.ogg")).await.ok()
+}
+
+/// Hitsound `name` from `dir` or the resource pack, `None` if neither has it.
+async fn find_clip(name: &str, dir: Option<&Path>, pack: &mut Option<Box<dyn FileSystem>>, rate: u32) -> Result<Option<Track>> {
+    let Some(data) = find_data(name, dir, pack).await else {
+        return Ok(None);
+    };
+    let clip = AudioClip::new(data).with_context(|| format!("failed to load hitsound {name}"))?;
+    Ok(Some(Track::of(&clip, rate)))
+}
+
+/// Hitsound `name` from `dir`, the resource pack or the built-in assets, whichever has it first.
+async fn load_clip(name: &str, dir: Option<&Path>, pack: &mut Option<Box<dyn FileSystem>>, rate: u32) -> Result<Track> {
+    match find_clip(name, dir, pack, rate).await? {
+        Some(track) => Ok(track),
+        None => {
+            let data = load_file(&format!("{name}.ogg")).await?;
+            let clip = AudioClip::new(data).with_context(|| format!("failed to load hitsound {name}"))?;
+            Ok(Track::of(&clip, rate))
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RpeChart {
+    #[serde(rename = "BPMList")]
+    bpm_list: Vec<RpeBpm>,
+    judge_line_list: Vec<RpeLine>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RpeBpm {
+    bpm: f64,
+    start_time: [f64; 3],
+}
+
+#[derive(Deserialize)]
+struct RpeLine {
+    #[serde(default)]
+    notes: Vec<RpeNote>,
+    #[serde(default = "default_bpm_factor", rename = "bpmfactor")]
+    bpm_factor: f64,
+}
+
+fn default_bpm_factor() -> f64 {
+    1.
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RpeNote {
+    #[serde(rename = "type")]
+    kind: u8,
+    start_time: [f64; 3],
+    #[serde(default)]
+    is_fake: u8,
+    #[serde(default)]
+    hitsound: Option<String>,
+}
+
+impl RpeNote {
+    /// [`NoteKind::order`] of the note, RPE numbers them tap, hold, flick, drag from 1.
+    fn order(&self) -> u8 {
+        match self.kind {
+            2 => 2,
+            3 => 3,
+            4 => 1,
+            _ => 0,
+        }
+    }
+}
+
+/// Beats of an RPE `[bar, numerator, denominator]` time.
+fn beats(time: [f64; 3]) -> f64 {
+    time[0] + if time[2] == 0. { 0. } else { time[1] / time[2] }
+}
+
+/// Custom hitsound paths of the played notes in chart order, `None` for notes without one. Empty
+/// unless it is an RPE chart, prpr doesn't keep them, so they are read from the chart file.
+async fn custom_hitsounds(fs: &mut dyn FileSystem, info: &ChartInfo) -> Vec<(NoteKey, Option<String>)> {
+    let Ok(data) = fs.load_file(&info.chart).await else {
+        return Vec::new();
+    };
+    let Ok(chart) = serde_json::from_slice::<RpeChart>(data.strip_prefix("\u{feff}".as_bytes()).unwrap_or(&data)) else {
+        return Vec::new();
+    };
+    let mut bpms: Vec<(f64, f64)> = chart.bpm_list.iter().map(|it| (beats(it.start_time), it.bpm)).collect();
+    bpms.sort_by(|a, b| a.0.total_cmp(&b.0));
+    let seconds = |beat: f64| {
+        let mut result = 0.;
+        for (i, &(start, bpm)) in bpms.iter().enumerate() {
+            if beat <= start {
+                break;
+            }
+            let end = bpms.get(i + 1).map_or(beat, |it| it.0.min(beat));
+            result += (end - start) * 60. / bpm;
+        }
+        result
+    };
+    let mut result = Vec::new();
+    for (index, line) in chart.judge_line_list.iter().enumerate() {
+        for note in line.notes.iter().filter(|it| it.is_fake == 0) {
+            let time = seconds(beats(note.start_time)) * line.bpm_factor;
+            let path = note.hitsound.clone().filter(|it| !it.is_empty());
+            result.push(((index, (time * 1000.).round() as i64, note.order()), path));
+        }
+    }
+    result
+}
@@ -1,10 +1,62 @@
+//! Messages from the `render` child to the GUI process.
+//!
+//! The child's stdout is shared with whatever prpr and macroquad print, so every message is
+//! written as one line starting with [`FRAME_PREFIX`] followed by the JSON length and the JSON
+//! itself. Lines without the prefix are plain log output.
+
+/// Bumped whenever `IPCEvent` changes incompatibly. The child announces it with
+/// `IPCEvent::Hello` before sending anything else.
+pub const PROTOCOL_VERSION: u32 = 3;
+
+const FRAME_PREFIX: &str = "\x1ePTK ";
+
 pub mod client {
+    use super::FRAME_PREFIX;
     use serde::Serialize;
+    use std::{
+        io::Write,
+        sync::atomic::{AtomicBool, Ordering},
+    };
+
+    static ENABLED: AtomicBool = AtomicBool::new(true);
+
+    /// Stops events from being written to stdout, used when there is no GUI process listening.
+    pub fn disable() {
+        ENABLED.store(false, Ordering::Relaxed);
+    }
 
     pub fn send<T: Serialize>(value: T) {
+        if !ENABLED.load(Ordering::Relaxed) {
+            return;
+        }
         match serde_json::to_string(&value) {
-            Ok(json) => println!("{}", json),
+            Ok(json) => {
+                // One locked write per frame so that concurrent prints can't split it
+                let mut stdout = std::io::stdout().lock();
+                let _ = writeln!(stdout, "{FRAME_PREFIX}{} {json}", json.len());
+                let _ = stdout.flush();
+            }
             Err(e) => eprintln!("Serialization error: {}", e),
         }
     }
-}
\ No newline at end of file
+}
+
+pub mod server {
+    use super::FRAME_PREFIX;
+    use anyhow::{bail, Context, Result};
+    use serde::de::DeserializeOwned;
+
+    /// Decodes one line of the child's stdout. Returns `None` for log output and an error for
+    /// damaged frames.
+    pub fn decode<T: DeserializeOwned>(line: &str) -> Option<Result<T>> {
+        let frame = line.trim_end().strip_prefix(FRAME_PREFIX)?;
+        Some((|| {
+            let (len, json) = frame.split_once(' ').context("missing frame length")?;
+            let len: usize = len.parse().context("invalid frame length")?;
+            if json.len() != len {
+                bail!("frame length mismatch: expected {len} bytes, got {}", json.len());
+            }
+            serde_json::from_str(json).context("invalid frame payload")
+        })())
+    }
+}
@@ -3,14 +3,20 @@
 
 prpr::tl_file!("main" mtl);
 
+mod cli;
 mod common;
+mod encoder;
+mod ffmpeg;
+mod hitsound;
 mod ipc;
+mod mixer;
 mod preview;
 mod render;
 mod task;
 
 use anyhow::{bail, Context, Result};
-use common::{ensure_dir, output_dir, respack_dir, CONFIG_DIR, DATA_DIR};
+use common::{default_asset_dir, ensure_dir, output_dir, respack_dir, CONFIG_DIR, DATA_DIR};
+use encoder::{CapabilityReport, EncoderProbe};
 use fs4::tokio::AsyncFileExt;
 use macroquad::prelude::set_pc_assets_folder;
 use prpr::{
@@ -30,7 +36,7 @@ use std::{
     sync::OnceLock,
     time::SystemTime,
 };
-use task::{TaskQueue, TaskView};
+use task::{QueueSettings, TaskQueue, TaskView};
 use tauri::{ipc::InvokeError, Manager, State, WindowEvent};
 use tokio::{io::AsyncWriteExt, process::Command};
 
@@ -68,6 +74,9 @@ pub async fn run() -> Result<()> {
 
     if std::env::args().len() > 1 {
         match std::env::args().skip(1).next().as_deref() {
+            Some("render") if cli::is_cli_invocation() => {
+                run_wrapped(cli::main()).await;
+            }
             Some("render") => {
                 run_wrapped(render::main()).await;
             }
@@ -99,6 +108,8 @@ pub async fn run() -> Result<()> {
             post_render,
             get_tasks,
             cancel_task,
+            get_queue_settings,
+            set_queue_settings,
             get_respacks,
             open_respack_folder,
             get_presets,
@@ -108,6 +119,8 @@ pub async fn run() -> Result<()> {
             unset_rpe_dir,
             get_rpe_charts,
             test_ffmpeg,
+            reprobe_encoders,
+            get_capability_report,
             open_app_folder,
             list_chart_files,
         ])
@@ -166,15 +179,7 @@ pub async fn run() -> Result<()> {
         ))
         .unwrap();
 
-    #[cfg(target_os = "linux")]
-    let asset_dir = {
-        PathBuf::from("/usr/lib/Phi-TK/assets")
-        // exe_dir.parent().unwrap().join("assets")
-    };
-
-    #[cfg(target_os = "windows")]
-    let asset_dir = exe_dir.join("assets");
-
+    let asset_dir = default_asset_dir()?;
     if !asset_dir.exists() {
         eprintln!("错误：资源目录不存在 - {:?}", asset_dir);
         bail!("资源目录未找到，请检查安装路径");
@@ -183,6 +188,10 @@ pub async fn run() -> Result<()> {
     ASSET_PATH.set(asset_dir.clone()).unwrap();
     set_pc_assets_folder(&asset_dir.display().to_string());
 
+    if let Err(err) = app.state::<TaskQueue>().restore().await {
+        eprintln!("Failed to restore task queue: {err:?}");
+    }
+
     app.run(|_, _| {});
 
     Ok(())
@@ -294,8 +303,17 @@ async fn get_tasks(queue: State<'_, TaskQueue>) -> Result<Vec<TaskView>, InvokeE
 
 #[tauri::command]
 async fn cancel_task(queue: State<'_, TaskQueue>, id: u32) -> Result<(), InvokeError> {
-    queue.cancel(id).await;
-    Ok(())
+    wrap_async(queue.cancel(id)).await
+}
+
+#[tauri::command]
+async fn get_queue_settings(queue: State<'_, TaskQueue>) -> Result<QueueSettings, InvokeError> {
+    Ok(queue.settings().await)
+}
+
+#[tauri::command]
+async fn set_queue_settings(queue: State<'_, TaskQueue>, settings: QueueSettings) -> Result<(), InvokeError> {
+    wrap_async(queue.set_settings(settings)).await
 }
 
 #[derive(Serialize)]
@@ -339,6 +357,8 @@ async fn batch_add_tasks(
                 path: PathBuf::from(path),
                 config,
                 info: ChartInfo::default(),
+                part: Default::default(),
+                encoders: None,
             };
             let output_path = output_path.map(PathBuf::from);
             queue.post(params, output_path).await.map_err(|e| anyhow::anyhow!("Failed to post task: {:?}", e))?;
@@ -600,6 +620,16 @@ fn test_ffmpeg() -> Result<bool, InvokeError> {
     (|| Ok(find_ffmpeg()?.is_some()))().map_err(InvokeError::from_anyhow)
 }
 
+#[tauri::command]
+async fn reprobe_encoders() -> Result<EncoderProbe, InvokeError> {
+    wrap_async(encoder::shared_probe(true)).await
+}
+
+#[tauri::command]
+async fn get_capability_report() -> Result<CapabilityReport, InvokeError> {
+    wrap_async(encoder::capability_report()).await
+}
+
 #[tauri::command]
 fn open_app_folder() -> Result<(), InvokeError> {
     (|| {
@@ -0,0 +1,409 @@
+//! Audio clips at the mixing rate and processing of the final mix. Every clip is resampled once
+//! up front with a windowed sinc filter, so the music, hitsounds and ending don't have to share a
+//! sample rate.
+
+use sasa::AudioClip;
+use serde::{Deserialize, Serialize};
+use std::{
+    f32::consts::{FRAC_PI_4, SQRT_2},
+    f64::consts::PI,
+};
+
+/// Stereo frames at `rate`.
+pub struct Track {
+    pub rate: u32,
+    pub frames: Vec<[f32; 2]>,
+}
+
+impl Track {
+    /// `clip` resampled to `rate`.
+    pub fn of(clip: &AudioClip, rate: u32) -> Self {
+        let frames: Vec<[f32; 2]> = clip.frames().iter().map(|it| [it.0, it.1]).collect();
+        Self {
+            rate,
+            frames: resample(&frames, clip.sample_rate(), rate),
+        }
+    }
+
+    pub fn duration(&self) -> f64 {
+        self.frames.len() as f64 / self.rate as f64
+    }
+
+    /// This track repeated for `duration` seconds, fading out over the last 10 ms so that it
+    /// doesn't end with a click.
+    pub fn looped(&self, duration: f64) -> Self {
+        let len = (duration.max(0.) * self.rate as f64).round() as usize;
+        let mut frames: Vec<[f32; 2]> = self.frames.iter().copied().cycle().take(len).collect();
+        let fade = (self.rate as usize / 100).min(frames.len());
+        let start = frames.len() - fade;
+        for (i, frame) in frames[start..].iter_mut().enumerate() {
+            let gain = 1. - (i + 1) as f32 / fade as f32;
+            frame[0] *= gain;
+            frame[1] *= gain;
+        }
+        Self { rate: self.rate, frames }
+    }
+}
+
+/// Constant power gains of the left and right channel for `pan` from -1 (left) to 1 (right),
+/// scaled so that both are 1 at the center.
+pub fn pan_gains(pan: f32) -> (f32, f32) {
+    let angle = (pan.clamp(-1., 1.) + 1.) * FRAC_PI_4;
+    (angle.cos() * SQRT_2, angle.sin() * SQRT_2)
+}
+
+/// Input frames on each side of an output frame.
+const HALF_TAPS: usize = 32;
+const TAPS: usize = HALF_TAPS * 2;
+/// Fractional positions the kernel is tabulated at.
+const PHASES: usize = 512;
+
+fn sinc(x: f64) -> f64 {
+    if x.abs() < 1e-9 {
+        1.
+    } else {
+        (PI * x).sin() / (PI * x)
+    }
+}
+
+/// Blackman-windowed sinc kernel for each of the [`PHASES`], normalized to unity gain.
+fn kernel(cutoff: f64) -> Vec<[f32; TAPS]> {
+    (0..PHASES)
+        .map(|phase| {
+            let frac = phase as f64 / PHASES as f64;
+            let mut weights = [0.; TAPS];
+            for (i, weight) in weights.iter_mut().enumerate() {
+                // Distance from the output position to input frame `i`
+                let t = i as f64 - (HALF_TAPS - 1) as f64 - frac;
+                let x = t / HALF_TAPS as f64;
+                let window = 0.42 + 0.5 * (PI * x).cos() + 0.08 * (2. * PI * x).cos();
+                *weight = cutoff * sinc(cutoff * t) * window;
+            }
+            let sum: f64 = weights.iter().sum();
+            weights.map(|it| (it / sum) as f32)
+        })
+        .collect()
+}
+
+/// Band-limited resampling of `input` from rate `from` to `to`. When downsampling, the cutoff
+/// moves down to the new Nyquist frequency so nothing aliases.
+pub fn resample(input: &[[f32; 2]], from: u32, to: u32) -> Vec<[f32; 2]> {
+    if from == to || input.is_empty() {
+        return input.to_vec();
+    }
+    let step = from as f64 / to as f64;
+    // Slightly below Nyquist, leaving room for the transition band
+    let kernel = kernel(0.95 * (1. / step).min(1.));
+    let len = (input.len() as f64 / step).ceil() as usize;
+    (0..len)
+        .map(|i| {
+            let position = i as f64 * step;
+            let index = position.floor();
+            let phase = (((position - index) * PHASES as f64) as usize).min(PHASES - 1);
+            let first = index as isize - (HALF_TAPS - 1) as isize;
+            let mut frame = [0.; 2];
+            for (j, weight) in kernel[phase].iter().enumerate() {
+                let Some(sample) = usize::try_from(first + j as isize).ok().and_then(|it| input.get(it)) else {
+                    continue;
+                };
+                frame[0] += sample[0] * weight;
+                frame[1] += sample[1] * weight;
+            }
+            frame
+        })
+        .collect()
+}
+
+/// Biquad filter in direct form I.
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    x: [f64; 2],
+    y: [f64; 2],
+}
+
+impl Biquad {
+    fn new(b: [f64; 3], a: [f64; 2]) -> Self {
+        Self { b, a, x: [0.; 2], y: [0.; 2] }
+    }
+
+    fn process(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.b[1] * self.x[0] + self.b[2] * self.x[1] - self.a[0] * self.y[0] - self.a[1] * self.y[1];
+        self.x = [x, self.x[0]];
+        self.y = [y, self.y[0]];
+        y
+    }
+}
+
+/// The two stages of the BS.1770 K-weighting filter, a high shelf modelling the head and a high
+/// pass, derived for `rate` the way libebur128 does it.
+fn k_weighting(rate: u32) -> [Biquad; 2] {
+    let rate = rate as f64;
+
+    let (f0, gain, q) = (1681.974450955533, 3.999843853973347, 0.7071752369554196);
+    let k = (PI * f0 / rate).tan();
+    let vh = 10f64.powf(gain / 20.);
+    let vb = vh.powf(0.4996667741545416);
+    let a0 = 1. + k / q + k * k;
+    let shelf = Biquad::new(
+        [(vh + vb * k / q + k * k) / a0, 2. * (k * k - vh) / a0, (vh - vb * k / q + k * k) / a0],
+        [2. * (k * k - 1.) / a0, (1. - k / q + k * k) / a0],
+    );
+
+    let (f0, q) = (38.13547087602444, 0.5003270373238773);
+    let k = (PI * f0 / rate).tan();
+    let a0 = 1. + k / q + k * k;
+    let high_pass = Biquad::new([1., -2., 1.], [2. * (k * k - 1.) / a0, (1. - k / q + k * k) / a0]);
+
+    [shelf, high_pass]
+}
+
+/// Integrated loudness of interleaved stereo `samples` in LUFS (EBU R128), `None` if everything
+/// is below the absolute gate.
+pub fn integrated_loudness(samples: &[f32], rate: u32) -> Option<f64> {
+    let mut filters = [k_weighting(rate), k_weighting(rate)];
+    // Gating blocks are 400 ms long and start every 100 ms, so they are sums of four steps
+    let step = (rate as usize / 10).max(1);
+    let mut steps = Vec::new();
+    let mut energy = 0.;
+    for (i, frame) in samples.chunks_exact(2).enumerate() {
+        for (channel, filters) in filters.iter_mut().enumerate() {
+            let weighted = filters.iter_mut().fold(frame[channel] as f64, |x, filter| filter.process(x));
+            energy += weighted * weighted;
+        }
+        if (i + 1) % step == 0 {
+            steps.push(std::mem::take(&mut energy));
+        }
+    }
+    let blocks: Vec<f64> = steps.windows(4).map(|it| it.iter().sum::<f64>() / (step * 4) as f64).collect();
+    let loudness = |power: f64| -0.691 + 10. * power.log10();
+    let gated_mean = |threshold: f64| {
+        let gated: Vec<f64> = blocks.iter().copied().filter(|it| loudness(*it) > threshold).collect();
+        (!gated.is_empty()).then(|| gated.iter().sum::<f64>() / gated.len() as f64)
+    };
+    let relative_gate = loudness(gated_mean(-70.)?) - 10.;
+    gated_mean(relative_gate.max(-70.)).map(loudness)
+}
+
+/// Peak of each frame of interleaved stereo `samples`, including the peaks between it and its
+/// neighbours, estimated by oversampling four times as BS.1770 does for the true peak.
+fn frame_peaks(samples: &[f32]) -> Vec<f32> {
+    const HALF: usize = 6;
+    const OVERSAMPLING: usize = 4;
+    let kernel: Vec<[f32; HALF * 2]> = (0..OVERSAMPLING)
+        .map(|phase| {
+            let frac = phase as f64 / OVERSAMPLING as f64;
+            let mut weights = [0.; HALF * 2];
+            for (i, weight) in weights.iter_mut().enumerate() {
+                let t = i as f64 - (HALF - 1) as f64 - frac;
+                let x = t / HALF as f64;
+                *weight = sinc(t) * (0.42 + 0.5 * (PI * x).cos() + 0.08 * (2. * PI * x).cos());
+            }
+            let sum: f64 = weights.iter().sum();
+            weights.map(|it| (it / sum) as f32)
+        })
+        .collect();
+    let mut peaks: Vec<f32> = samples.chunks_exact(2).map(|frame| frame[0].abs().max(frame[1].abs())).collect();
+    for channel in 0..2 {
+        let input: Vec<f32> = samples.iter().skip(channel).step_by(2).copied().collect();
+        // Window `j` interpolates between frames `j + HALF - 1` and `j + HALF`, phase 0 is the
+        // samples themselves
+        for (j, window) in input.windows(HALF * 2).enumerate() {
+            for weights in &kernel[1..] {
+                let value = window.iter().zip(weights).map(|(x, w)| x * w).sum::<f32>().abs();
+                for peak in &mut peaks[j + HALF - 1..=j + HALF] {
+                    *peak = peak.max(value);
+                }
+            }
+        }
+    }
+    peaks
+}
+
+/// Highest absolute value of interleaved stereo `samples` oversampled four times, as BS.1770
+/// estimates the true peak between the samples.
+pub fn true_peak(samples: &[f32]) -> f32 {
+    frame_peaks(samples).into_iter().fold(0., f32::max)
+}
+
+/// Lookahead limiter keeping the true peaks of interleaved stereo `samples` at or below
+/// `ceiling`. The gain ramps down over 5 ms to reach its minimum exactly at a peak and recovers
+/// over about 100 ms. Returns whether any frame was turned down.
+pub fn limit(samples: &mut [f32], rate: u32, ceiling: f32) -> bool {
+    let required: Vec<f32> = frame_peaks(samples)
+        .into_iter()
+        .map(|peak| if peak > ceiling { ceiling / peak } else { 1. })
+        .collect();
+    if required.iter().all(|it| *it >= 1.) {
+        return false;
+    }
+    let frames = required.len();
+    let lookahead = (rate as usize / 200).max(1);
+    let release = 1. - (-1. / (rate as f32 * 0.1)).exp();
+
+    // Lowest requirement in the window of `lookahead` frames starting at each frame
+    let mut target = vec![1f32; frames];
+    let mut window = std::collections::VecDeque::new();
+    for i in (0..frames).rev() {
+        while window.back().is_some_and(|&j| required[j] >= required[i]) {
+            window.pop_back();
+        }
+        window.push_back(i);
+        while window.front().is_some_and(|&j| j >= i + lookahead) {
+            window.pop_front();
+        }
+        target[i] = required[*window.front().unwrap()];
+    }
+
+    // Averaging over the lookahead turns the steps into ramps that never exceed any of the
+    // requirements they cover
+    let mut envelope = 1f32;
+    let mut history = std::collections::VecDeque::with_capacity(lookahead);
+    let mut sum = 0f64;
+    for (i, frame) in samples.chunks_exact_mut(2).enumerate() {
+        envelope = target[i].min(envelope + (1. - envelope) * release);
+        history.push_back(envelope);
+        sum += envelope as f64;
+        if history.len() > lookahead {
+            sum -= history.pop_front().unwrap() as f64;
+        }
+        let gain = (sum / history.len() as f64) as f32;
+        frame[0] *= gain;
+        frame[1] *= gain;
+    }
+    true
+}
+
+/// Loudness of the final mix, shown with the task.
+#[derive(Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "camelCase")]
+pub struct LoudnessReport {
+    /// Integrated loudness in LUFS, `None` for silence.
+    pub integrated: Option<f64>,
+    /// True peak in dBTP, `None` for silence.
+    pub true_peak: Option<f64>,
+    /// Gain applied by loudness normalization in dB.
+    pub gain: f64,
+    /// Whether the limiter had to turn anything down.
+    pub limited: bool,
+}
+
+impl LoudnessReport {
+    pub fn measure(samples: &[f32], rate: u32, gain: f64, limited: bool) -> Self {
+        let peak = true_peak(samples);
+        Self {
+            integrated: integrated_loudness(samples, rate),
+            true_peak: (peak > 0.).then(|| 20. * (peak as f64).log10()),
+            gain,
+            limited,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: u32 = 48000;
+
+    /// Interleaved stereo sine with peak `amplitude`, in the right channel as well if `stereo`.
+    fn sine(frequency: f64, amplitude: f32, seconds: f64, stereo: bool) -> Vec<f32> {
+        (0..(seconds * RATE as f64) as usize)
+            .flat_map(|i| {
+                let value = amplitude * (2. * PI * frequency * i as f64 / RATE as f64).sin() as f32;
+                [value, if stereo { value } else { 0. }]
+            })
+            .collect()
+    }
+
+    fn sample_peak(samples: &[f32]) -> f32 {
+        samples.iter().fold(0., |peak, it| peak.max(it.abs()))
+    }
+
+    fn rms(frames: &[[f32; 2]]) -> f64 {
+        (frames.iter().map(|it| (it[0] as f64).powi(2)).sum::<f64>() / frames.len() as f64).sqrt()
+    }
+
+    #[test]
+    fn resampling_keeps_dc() {
+        for (from, to) in [(44100, 48000), (48000, 44100), (22050, 48000)] {
+            let output = resample(&vec![[0.5, -0.25]; from as usize], from, to);
+            assert_eq!(output.len(), to as usize);
+            // Away from the edges, where the kernel runs past the input
+            for frame in &output[HALF_TAPS * 2..output.len() - HALF_TAPS * 2] {
+                assert!((frame[0] - 0.5).abs() < 1e-3 && (frame[1] + 0.25).abs() < 1e-3, "{from} -> {to}: {frame:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn resampling_keeps_sine_level() {
+        for (from, to) in [(44100, 48000), (48000, 44100), (96000, 48000)] {
+            let input: Vec<[f32; 2]> = (0..from as usize)
+                .map(|i| {
+                    let value = 0.5 * (2. * PI * 1000. * i as f64 / from as f64).sin() as f32;
+                    [value, value]
+                })
+                .collect();
+            let output = resample(&input, from, to);
+            let edge = HALF_TAPS * 2;
+            let level = 20. * (rms(&output[edge..output.len() - edge]) / rms(&input[edge..input.len() - edge])).log10();
+            assert!(level.abs() < 0.05, "{from} -> {to}: {level} dB");
+        }
+    }
+
+    #[test]
+    fn resampling_to_the_same_rate_is_a_copy() {
+        let input = vec![[0.1, 0.2], [0.3, 0.4]];
+        assert_eq!(resample(&input, 48000, 48000), input);
+    }
+
+    #[test]
+    fn loudness_of_reference_sines() {
+        // -20 dBFS in one channel is the EBU R128 reference level
+        let loudness = integrated_loudness(&sine(997., 0.1, 10., false), RATE).unwrap();
+        assert!((loudness + 23.).abs() < 0.05, "{loudness}");
+        // EBU Tech 3341 case 1, -23 dBFS in both channels
+        let loudness = integrated_loudness(&sine(1000., 10f32.powf(-23. / 20.), 20., true), RATE).unwrap();
+        assert!((loudness + 23.).abs() < 0.05, "{loudness}");
+    }
+
+    #[test]
+    fn loudness_of_silence() {
+        assert_eq!(integrated_loudness(&vec![0.; RATE as usize * 4], RATE), None);
+    }
+
+    #[test]
+    fn true_peak_between_samples() {
+        // A quarter of the sample rate shifted by 45°, so every sample misses the peak by 3 dB
+        let samples: Vec<f32> = (0..RATE as usize)
+            .flat_map(|i| {
+                let value = (PI / 2. * i as f64 + PI / 4.).sin() as f32;
+                [value, value]
+            })
+            .collect();
+        assert!((sample_peak(&samples) - 0.5f32.sqrt()).abs() < 1e-3);
+        assert!(true_peak(&samples) > 0.95);
+    }
+
+    #[test]
+    fn limiter_keeps_true_peaks_below_ceiling() {
+        let ceiling = 10f32.powf(-1. / 20.);
+        let mut samples = sine(997., 2., 2., true);
+        // A sudden burst that the lookahead has to catch
+        for (i, sample) in samples[RATE as usize..].iter_mut().take(480).enumerate() {
+            *sample += if i % 4 < 2 { 3. } else { -3. };
+        }
+        assert!(limit(&mut samples, RATE, ceiling));
+        assert!(sample_peak(&samples) <= ceiling);
+        assert!(true_peak(&samples) <= ceiling * 1.001, "{}", true_peak(&samples));
+    }
+
+    #[test]
+    fn limiter_leaves_quiet_mixes_alone() {
+        let original = sine(997., 0.5, 1., true);
+        let mut samples = original.clone();
+        assert!(!limit(&mut samples, RATE, 0.9));
+        assert_eq!(samples, original);
+    }
+}
@@ -1,10 +1,17 @@
 // Prevents additional console window on Windows in release, DO NOT REMOVE!!
 prpr::tl_file!("render");
 
-use crate::Path;
+use crate::{
+    common::default_config_dir,
+    encoder::{self, EncoderProbe, EncoderSpec, Vendor, ENCODERS},
+    ffmpeg::{user_args, FfmpegCommand, Input, Output},
+    hitsound::Hitsounds,
+    mixer::{integrated_loudness, limit, pan_gains, LoudnessReport, Track},
+};
 use anyhow::{bail, Context, Result};
+use async_trait::async_trait;
 use macroquad::{miniquad::{gl::{
-    GLuint, GL_RGBA, GL_UNSIGNED_BYTE, GL_READ_FRAMEBUFFER, GL_PIXEL_PACK_BUFFER,
+    GLenum, GLuint, GL_RGBA, GL_UNSIGNED_BYTE, GL_UNSIGNED_SHORT, GL_READ_FRAMEBUFFER, GL_PIXEL_PACK_BUFFER,
     GL_STREAM_READ, GL_MAP_READ_BIT, GL_MAP_UNSYNCHRONIZED_BIT,
     GLsizeiptr, GLsizei, GLvoid,
     glGenBuffers, glBindBuffer, glBufferData, glDeleteBuffers,
@@ -14,9 +21,9 @@ use macroquad::{miniquad::{gl::{
 }, RenderPass as MQRenderPass}, prelude::*};
 use prpr::{
     config::{ChallengeModeColor, Config, Mods},
-    core::{internal_id, MSRenderTarget, NoteKind},
+    core::{internal_id, JudgeLine, MSRenderTarget, NoteKind},
     ext::SafeTexture,
-    fs,
+    fs::{self, FileSystem},
     info::ChartInfo,
     scene::{BasicPlayer, GameMode, GameScene, LoadingScene},
     time::TimeManager,
@@ -26,11 +33,12 @@ use prpr::{
 use sasa::AudioClip;
 use serde::{Deserialize, Serialize};
 use std::{
+    any::Any,
     cell::RefCell,
     io::{BufRead, BufWriter, Write},
-    ops::DerefMut,
-    path::PathBuf,
-    process::{Command, Stdio},
+    ops::{DerefMut, Range},
+    path::{Path, PathBuf},
+    process::{Child, ChildStdin, Command, Stdio},
     rc::Rc,
     sync::atomic::{AtomicBool, Ordering},
     time::Instant,
@@ -55,11 +63,14 @@ pub struct RenderConfig {
     pub fps: u32,
     pub hardware_accel: bool,
     pub video_codec: String,
-    pub encoder: String, // 'auto', 'nvenc', 'qsv', 'amf', 'vulkan', 'cpu'
+    pub encoder: String, // 'auto', 'nvenc', 'qsv', 'amf', 'vaapi', 'vulkan', 'cpu'
     pub show_progress_text: bool,
     pub show_time_text: bool,
     pub target_audio: u32,
     pub autoplay: Option<bool>,
+    /// `"CRF"`, `"CBR"`, `"VBR"` (peaking at `max_bitrate`), `"TwoPass"` or `"TargetSize"`
+    /// (bitrate derived from `target_size`). The last two encode in two passes if a software
+    /// encoder is used, see [`RenderConfig::two_pass`].
     pub bitrate_control: String,
     pub bitrate: String,
     pub watermark: String,
@@ -84,6 +95,16 @@ pub struct RenderConfig {
     pub speed: f32,
     pub volume_music: f32,
     pub volume_sfx: f32,
+    /// Per-kind multipliers on `volume_sfx`.
+    pub volume_click: f32,
+    pub volume_drag: f32,
+    pub volume_hold: f32,
+    pub volume_flick: f32,
+    /// Folder with `click`, `drag` and `flick` clips (ogg, wav or mp3) replacing those of the
+    /// resource pack. Missing ones fall back to the pack.
+    pub hitsound_dir: Option<String>,
+    /// Sustain and release sounds for holds, when the hitsound folder or resource pack has them.
+    pub hold_sounds: bool,
 
     pub hand_split: bool,
     pub note_speed_factor: f32,
@@ -99,6 +120,65 @@ pub struct RenderConfig {
 
     //ffmpeg
     pub ffmpeg_thread: bool,
+    /// Encode the video in chunks of this many seconds so that an interrupted render can resume
+    /// from the last finished chunk. `0` writes a single stream.
+    pub segment_length: f64,
+    /// Split the video into this many time ranges rendered by parallel processes. Segmented
+    /// output is not used for sliced renders.
+    pub render_slices: u32,
+    /// `"video"`, `"audio"` to only export the mixed audio track without rendering frames, or
+    /// `"frames"` for a directory of numbered images plus the track as WAV.
+    pub output_mode: String,
+    /// `"png"`, `"exr"` or `"webp"`, used by the `"frames"` output mode.
+    pub image_format: String,
+    /// Render without the illustration and background dim and keep the alpha channel.
+    pub transparent: bool,
+    /// `"prores"` (ProRes 4444), `"qtrle"` (QuickTime Animation) or `"vp9"` (WebM), used when
+    /// `transparent` is set.
+    pub alpha_codec: String,
+    /// `-preset` of the SVT-AV1 software encoder, from 0 (slowest) to 13.
+    pub svt_av1_preset: u32,
+    /// `-speed` of the rav1e software encoder, from 0 (slowest) to 10. Its quality takes a
+    /// quantizer from 0 to 255 instead of a CRF.
+    pub rav1e_speed: u32,
+    /// Peak bitrate for `"VBR"`, e.g. `"12M"`. Empty for 1.5 times `bitrate`.
+    pub max_bitrate: String,
+    /// Rate control buffer size for `"VBR"`. Empty for twice `bitrate`.
+    pub rate_buffer: String,
+    /// Size of the output in MB for `"TargetSize"`.
+    pub target_size: f64,
+    /// `8` or `10`. 10-bit frames are read back at 16 bits per channel and encoded as HEVC
+    /// Main10 or 10-bit AV1.
+    pub bit_depth: u32,
+    /// Map the video to BT.2020 PQ and tag it as HDR10, requires a `bit_depth` of 10.
+    pub hdr: bool,
+    /// `"bt709"` or `"bt601"`, the matrix of the YUV conversion and the tags written with it.
+    /// Ignored for HDR.
+    pub color_space: String,
+    /// `"limited"` or `"full"`.
+    pub color_range: String,
+    /// Pan each hitsound by where its note is hit on the screen.
+    pub positional_hitsound: bool,
+    /// Pan of notes hit at the screen edges with `positional_hitsound`, from 0 to 1 (hard left
+    /// or right).
+    pub hitsound_pan: f32,
+    /// Scale the mix to `target_lufs` integrated loudness (EBU R128).
+    pub loudness_normalize: bool,
+    /// E.g. `-14` for most streaming platforms.
+    pub target_lufs: f64,
+    /// Keep the peaks of the mix below `limiter_ceiling` so that dense charts don't clip.
+    pub limiter: bool,
+    /// In dBFS.
+    pub limiter_ceiling: f64,
+    /// Rate the audio is mixed at before it is converted to `target_audio`. `0` mixes at 48 kHz
+    /// for videos and at `target_audio` for standalone tracks.
+    pub mix_sample_rate: u32,
+    /// Extra options of the raw video input, split like a shell would.
+    pub extra_input_args: String,
+    /// Extra options of the video encoder output, like `-tune animation -g 120`.
+    pub extra_output_args: String,
+    /// Filter chain run on the upright RGB frames before the color conversion, like `unsharp`.
+    pub extra_video_filters: String,
 }
 
 impl Default for RenderConfig {
@@ -143,6 +223,12 @@ impl Default for RenderConfig {
             speed: 1.0,
             volume_music: 1.0,
             volume_sfx: 1.0,
+            volume_click: 1.0,
+            volume_drag: 1.0,
+            volume_hold: 1.0,
+            volume_flick: 1.0,
+            hitsound_dir: None,
+            hold_sounds: true,
             hand_split: false,
             note_speed_factor: 1.0,
             video: false,
@@ -159,6 +245,31 @@ impl Default for RenderConfig {
 
             //ffmpeg
             ffmpeg_thread: false,
+            segment_length: 0.,
+            render_slices: 1,
+            output_mode: "video".to_owned(),
+            image_format: "png".to_owned(),
+            transparent: false,
+            alpha_codec: "prores".to_owned(),
+            svt_av1_preset: 8,
+            rav1e_speed: 8,
+            max_bitrate: String::new(),
+            rate_buffer: String::new(),
+            target_size: 100.,
+            bit_depth: 8,
+            hdr: false,
+            color_space: "bt709".to_owned(),
+            color_range: "limited".to_owned(),
+            positional_hitsound: false,
+            hitsound_pan: 0.6,
+            loudness_normalize: false,
+            target_lufs: -14.,
+            limiter: true,
+            limiter_ceiling: -1.,
+            mix_sample_rate: 0,
+            extra_input_args: String::new(),
+            extra_output_args: String::new(),
+            extra_video_filters: String::new(),
         }
     }
 }
@@ -206,182 +317,189 @@ impl RenderConfig {
             ..Default::default()
         }
     }
+
+    pub fn audio_only(&self) -> bool {
+        self.output_mode == "audio"
+    }
+
+    pub fn image_sequence(&self) -> bool {
+        self.output_mode == "frames"
+    }
+
+    pub fn mixing_rate(&self) -> u32 {
+        match self.mix_sample_rate {
+            0 if self.audio_only() || self.image_sequence() => self.target_audio,
+            0 => 48000,
+            rate => rate,
+        }
+    }
+
+    /// Whether `render_slices` applies, the other output modes always render in one process.
+    pub fn can_slice(&self) -> bool {
+        self.output_mode == "video" && !self.transparent
+    }
+
+    /// Whether the video is encoded in two passes, run as separate processes by the task queue.
+    /// Only software encoders support it, so it requires them to be picked.
+    pub fn two_pass(&self) -> bool {
+        matches!(self.bitrate_control.as_str(), "TwoPass" | "TargetSize")
+            && (!self.hardware_accel || self.encoder == "cpu")
+            && self.can_slice()
+    }
+
+    fn alpha_container(&self) -> &'static str {
+        match self.alpha_codec.as_str() {
+            "vp9" => "webm",
+            _ => "mov",
+        }
+    }
+
+    /// File extension of the rendered output, `None` if the output is a directory.
+    pub fn output_extension(&self) -> Option<&'static str> {
+        if self.image_sequence() {
+            return None;
+        }
+        if self.audio_only() {
+            if self.audio_bit.is_some() {
+                return Some("wav");
+            }
+            return Some(match self.audio_format.to_lowercase().as_str() {
+                "flac" => "flac",
+                "mp3" => "mp3",
+                "opus" => "opus",
+                "wav" => "wav",
+                _ => "m4a",
+            });
+        }
+        if self.transparent {
+            return Some(self.alpha_container());
+        }
+        Some(match self.video {
+            true => "mov",
+            false => "mp4",
+        })
+    }
 }
 
-#[derive(Deserialize, Serialize)]
+#[derive(Deserialize, Serialize, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct RenderParams {
     pub path: PathBuf,
     pub info: ChartInfo,
     pub config: RenderConfig,
+    #[serde(default)]
+    pub part: RenderPart,
+    /// Filled in by the task queue right before spawning the render process.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encoders: Option<EncoderProbe>,
+}
+
+/// Which part of the output a render process produces. Charts rendered with
+/// `RenderConfig::render_slices` are split into several `Slice`s and one `Audio` part that the
+/// task queue stitches together afterwards.
+#[derive(Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase", tag = "type")]
+pub enum RenderPart {
+    #[default]
+    Full,
+    /// Video-only file with the `index`-th of `count` equal time ranges.
+    Slice { index: u32, count: u32 },
+    /// Only the mixed audio track.
+    Audio,
+    /// First pass of a two-pass encode, only writes the encoder statistics (see [`pass_log`]).
+    Analysis,
 }
 
+/// Sent by the render child, see [`crate::ipc`].
 #[derive(Serialize, Deserialize)]
 pub enum IPCEvent {
+    /// Always the first event, `version` is the child's [`crate::ipc::PROTOCOL_VERSION`].
+    Hello { version: u32 },
     StartMixing,
+    /// Sent once rendering starts, with the number of frames that will be encoded.
     StartRender(u64),
-    Frame,
+    /// All frames are rendered, ffmpeg is finishing or the parts are being joined.
+    StartFinalizing,
+    /// The ffmpeg video encoder that was selected.
+    Encoder(String),
+    /// Measured on the final mix before it is encoded.
+    Loudness(LoudnessReport),
+    /// One frame was encoded, `elapsed` is the time it took in seconds.
+    Frame { elapsed: f32 },
+    Warning(String),
+    Error {
+        kind: ErrorKind,
+        message: String,
+        context_chain: Vec<String>,
+        /// Localized suggestion on how to fix it.
+        hint: Option<String>,
+    },
     Done(f64),
 }
 
-struct EncoderAvailability {
-    h264_nvenc: bool,
-    hevc_nvenc: bool,
-    h264_qsv: bool,
-    hevc_qsv: bool,
-    h264_amf: bool,
-    hevc_amf: bool,
-    av1_nvenc: bool,
-    av1_amf: bool,
-    av1_qsv: bool,
-    h264_cuvid: bool,
-    hevc_cuvid: bool,
-    av1_cuvid: bool,
-    // Vulkan encoders
-    h264_vulkan: bool,
-    hevc_vulkan: bool,
-    av1_vulkan: bool,
+/// Category of a failed render. Attach it as context (`.context(ErrorKind::ChartLoad)`) or bail
+/// with it to classify an error, see [`ErrorKind::of`].
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+    ChartLoad,
+    FfmpegMissing,
+    EncoderUnavailable,
+    InvalidConfig,
+    DiskFull,
+    #[default]
+    Other,
 }
 
-#[cfg(target_os = "windows")]
-mod hw_detect {
-    use std::path::Path;
-    use winreg::{enums::HKEY_LOCAL_MACHINE, RegKey};
-
-    pub fn detect_nvidia() -> bool {
-        use std::process::Command;
-        use winreg::enums::HKEY_LOCAL_MACHINE;
-        use winreg::RegKey;
-        use std::path::Path;
-
-        if let Ok(key) = RegKey::predef(HKEY_LOCAL_MACHINE)
-            .open_subkey(r"SYSTEM\CurrentControlSet\Control\Class\{4d36e968-e325-11ce-bfc1-08002be10318}")
-        {
-            for subkey_name in key.enum_keys().filter_map(|x| x.ok()) {
-                if let Ok(subkey) = key.open_subkey(&subkey_name) {
-                    if let Ok(provider) = subkey.get_value::<String, _>("ProviderName") {
-                        if provider.to_lowercase().contains("nvidia") {
-                            return true;
-                        }
-                    }
-                }
-            }
-        }
-        if Path::new(r"C:\Windows\System32\nvcuda.dll").exists() {
-            return true;
+impl ErrorKind {
+    pub fn of(err: &anyhow::Error) -> Self {
+        if let Some(kind) = err.downcast_ref::<Self>() {
+            return *kind;
         }
-        Command::new("nvidia-smi").output().is_ok()
-    }
-
-    pub fn detect_intel_qsv() -> bool {
-        let mut found = false;
-        let classes = [
-            "{4d36e968-e325-11ce-bfc1-08002be10318}", // Display adapters
-            "{4d36e97d-e325-11ce-bfc1-08002be10318}", // System devices
-        ];
-
-        for class in classes {
-            if let Ok(key) = RegKey::predef(HKEY_LOCAL_MACHINE)
-                .open_subkey(format!(r"SYSTEM\CurrentControlSet\Control\Class\{}", class))
-            {
-                for subkey in key.enum_keys().filter_map(|x| x.ok()) {
-                    if let Ok(subkey) = key.open_subkey(subkey) {
-                        if let Ok(provider) = subkey.get_value::<String, _>("ProviderName") {
-                            if provider.contains("Intel") {
-                                found = true;
-                                break;
-                            }
-                        }
-                    }
-                }
-            }
+        #[cfg(windows)]
+        const DISK_FULL: &[i32] = &[39, 112]; // ERROR_HANDLE_DISK_FULL, ERROR_DISK_FULL
+        #[cfg(not(windows))]
+        const DISK_FULL: &[i32] = &[28]; // ENOSPC
+        let disk_full = err
+            .chain()
+            .filter_map(|it| it.downcast_ref::<std::io::Error>())
+            .any(|it| matches!(it.raw_os_error(), Some(code) if DISK_FULL.contains(&code)));
+        if disk_full {
+            Self::DiskFull
+        } else {
+            Self::Other
         }
-        found
-    }
-
-    pub fn detect_amd() -> bool {
-        Path::new(r"C:\Windows\System32\amdvlk64.dll").exists()
-            || Path::new(r"C:\Windows\System32\amfrt64.dll").exists()
     }
 
-    pub fn detect_vulkan() -> bool {
-        // Check for Vulkan runtime (Vulkan Loader)
-        Path::new(r"C:\Windows\System32\vulkan-1.dll").exists()
+    pub fn hint(self) -> Option<String> {
+        Some(
+            match self {
+                Self::ChartLoad => tl!("hint-chart-load"),
+                Self::FfmpegMissing => tl!("hint-ffmpeg-missing"),
+                Self::EncoderUnavailable => tl!("hint-encoder-unavailable"),
+                Self::InvalidConfig => tl!("hint-invalid-config"),
+                Self::DiskFull => tl!("hint-disk-full"),
+                Self::Other => return None,
+            }
+            .to_string(),
+        )
     }
 }
 
-#[cfg(target_os = "linux")]
-mod hw_detect {
-    use std::path::Path;
-    use std::process::Command;
-
-    pub fn detect_nvidia() -> bool {
-        Path::new("/dev/nvidia0").exists() || Command::new("nvidia-smi").status().is_ok()
-    }
-
-    pub fn detect_intel_qsv() -> bool {
-        Path::new("/dev/dri/renderD128").exists()
-            && Command::new("vainfo")
-            .output()
-            .map(|out| String::from_utf8_lossy(&out.stdout).contains("VAProfileH264"))
-            .unwrap_or(false)
-    }
-
-    pub fn detect_amd() -> bool {
-        Path::new("/dev/kfd").exists()
-            && Command::new("vainfo")
-            .output()
-            .map(|out| String::from_utf8_lossy(&out.stdout).contains("AMD"))
-            .unwrap_or(false)
-    }
-
-    pub fn detect_vulkan() -> bool {
-        // Check for Vulkan ICD files (indicates Vulkan driver is installed)
-        Path::new("/usr/share/vulkan/icd.d").exists()
-            || Path::new("/etc/vulkan/icd.d").exists()
-            || Path::new("/usr/local/share/vulkan/icd.d").exists()
+impl std::fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&match self {
+            Self::ChartLoad => tl!("load-chart-failed"),
+            Self::FfmpegMissing => tl!("ffmpeg-not-found"),
+            Self::EncoderUnavailable => tl!("no-hwacc"),
+            Self::InvalidConfig => tl!("invalid-config"),
+            Self::DiskFull => tl!("disk-full"),
+            Self::Other => tl!("render-failed"),
+        })
     }
 }
 
-#[cfg(target_os = "macos")]
-mod hw_detect {
-    use std::process::Command;
-
-    pub fn detect_nvidia() -> bool {
-        Command::new("system_profiler")
-            .args(&["SPDisplaysDataType"])
-            .output()
-            .map(|out| String::from_utf8_lossy(&out.stdout).contains("NVIDIA"))
-            .unwrap_or(false)
-    }
-
-    pub fn detect_intel_qsv() -> bool {
-        Command::new("system_profiler")
-            .args(&["SPDisplaysDataType"])
-            .output()
-            .map(|out| String::from_utf8_lossy(&out.stdout).contains("Intel"))
-            .unwrap_or(false)
-    }
-
-    pub fn detect_amd() -> bool {
-        Command::new("system_profiler")
-            .args(&["SPDisplaysDataType"])
-            .output()
-            .map(|out| String::from_utf8_lossy(&out.stdout).contains("AMD"))
-            .unwrap_or(false)
-    }
-
-    pub fn detect_vulkan() -> bool {
-        // macOS uses MoltenVK for Vulkan support
-        // Check for MoltenVK or Vulkan loader
-        Command::new("sh")
-            .arg("-c")
-            .arg("ls /usr/local/lib/libMoltenVK.dylib 2>/dev/null || ls /opt/homebrew/lib/libMoltenVK.dylib 2>/dev/null || ls ~/Library/Frameworks/libMoltenVK.dylib 2>/dev/null")
-            .status()
-            .map(|s| s.success())
-            .unwrap_or(false)
-    }
-}
+impl std::error::Error for ErrorKind {}
 
 pub async fn build_player(config: &RenderConfig) -> Result<BasicPlayer> {
     Ok(BasicPlayer {
@@ -454,195 +572,610 @@ pub fn find_ffmpeg() -> Result<Option<String>> {
     })
 }
 
-pub async fn main() -> Result<()> {
-    use crate::ipc::client::*;
-
-    set_pc_assets_folder(&std::env::args().nth(2).unwrap());
+/// Where the frames read back from the GPU go.
+enum VideoOutput {
+    /// One ffmpeg process encoding `frames` straight into the output file.
+    Single {
+        proc: Child,
+        input: ChildStdin,
+        frames: Range<u64>,
+    },
+    Segmented(SegmentedOutput),
+}
 
-    let mut stdin = std::io::stdin().lock();
-    let stdin = &mut stdin;
+impl VideoOutput {
+    /// Whether `frame` still has to be read back and encoded.
+    fn wants(&self, frame: u64) -> bool {
+        match self {
+            Self::Single { frames, .. } => frames.contains(&frame),
+            Self::Segmented(segments) => segments.wants(frame),
+        }
+    }
 
-    let mut line = String::new();
-    stdin.read_line(&mut line)?;
-    let params: RenderParams = serde_json::from_str(line.trim())?;
-    let path = params.path;
+    /// First frame that has to be encoded.
+    fn start(&self) -> u64 {
+        match self {
+            Self::Single { frames, .. } => frames.start,
+            Self::Segmented(segments) => segments.start(),
+        }
+    }
 
-    line.clear();
-    stdin.read_line(&mut line)?;
-    let output_path: PathBuf = serde_json::from_str(line.trim())?;
+    /// First frame after which nothing is encoded any more.
+    fn end(&self) -> u64 {
+        match self {
+            Self::Single { frames, .. } => frames.end,
+            Self::Segmented(_) => u64::MAX,
+        }
+    }
 
-    let mut fs = fs::fs_from_file(&path)?;
+    fn write_frame(&mut self, frame: u64, data: &[u8]) -> Result<()> {
+        match self {
+            Self::Single { input, .. } => input.write_all(data)?,
+            Self::Segmented(segments) => segments.write_frame(frame, data)?,
+        }
+        Ok(())
+    }
+}
 
-    let font = FontArc::try_from_vec(load_file("font.ttf").await?)?;
+/// Horizontal screen position of every note when it is hit, from -1 (left edge) to 1, by line.
+/// The lines' animations are rewound afterwards.
+fn hit_positions(lines: &mut [JudgeLine], aspect_ratio: f32) -> Vec<Vec<f32>> {
+    let notes: Vec<Vec<(f32, f32)>> = lines
+        .iter()
+        .map(|line| line.notes.iter().map(|note| (note.time, note.object.translation.0.now())).collect())
+        .collect();
+    let mut positions = Vec::with_capacity(notes.len());
+    for (index, notes) in notes.into_iter().enumerate() {
+        let line: Vec<f32> = notes
+            .into_iter()
+            .map(|(time, x)| {
+                let ((line_x, _), rotation) = line_transform(lines, index, time, aspect_ratio);
+                (line_x + rotation.cos() * x).clamp(-1., 1.)
+            })
+            .collect();
+        positions.push(line);
+    }
+    for line in lines.iter_mut() {
+        line.object.translation.0.set_time(0.);
+        line.object.translation.1.set_time(0.);
+        line.object.rotation.set_time(0.);
+    }
+    positions
+}
 
-    let Some(ffmpeg) = find_ffmpeg()? else {
-        bail!("FFmpeg not found")
+/// Position and rotation (in radians) of line `index` at `time`. A child line is placed like on
+/// screen: its translation, with y scaled to x units, is rotated by its parent's rotation and
+/// added to the parent's position.
+fn line_transform(lines: &mut [JudgeLine], index: usize, time: f32, aspect_ratio: f32) -> ((f32, f32), f32) {
+    let object = &mut lines[index].object;
+    object.translation.0.set_time(time);
+    object.translation.1.set_time(time);
+    object.rotation.set_time(time);
+    let (x, y) = (object.translation.0.now(), object.translation.1.now() / aspect_ratio);
+    let rotation = object.rotation.now().to_radians();
+    let Some(parent) = lines[index].parent else {
+        return ((x, y), rotation);
     };
-    info!("Using ffmpeg: {}", ffmpeg);
+    let ((parent_x, parent_y), parent_rotation) = line_transform(lines, parent, time, aspect_ratio);
+    let (sin, cos) = parent_rotation.sin_cos();
+    ((parent_x + cos * x - sin * y, parent_y + sin * x + cos * y), rotation)
+}
 
-    let mut painter = TextPainter::new(font);
+/// Starts `command`, which reads rawvideo from stdin.
+fn spawn_encoder(ffmpeg: &str, command: &FfmpegCommand) -> Result<(Child, ChildStdin)> {
+    let mut proc = command
+        .command(ffmpeg)
+        .stdin(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .spawn()
+        .with_context(|| tl!("run-ffmpeg-failed"))?;
+    let input = proc.stdin.take().unwrap();
+    Ok((proc, input))
+}
 
-    let mut config = params.config.to_config();
-    config.mods = Mods::AUTOPLAY;
+/// Directory and file name prefix of the first pass statistics. Encoders get the name relative to
+/// the directory since libx265 can't take paths with drive letters.
+pub fn pass_log(output: &Path) -> (PathBuf, String) {
+    let dir = output.parent().filter(|it| !it.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    let name = format!("{}.2pass", output.file_name().unwrap_or_default().to_string_lossy());
+    (dir.to_owned(), name)
+}
 
-    let info = params.info;
+/// Removes the statistics files written by the first pass, whose names depend on the encoder.
+pub fn remove_pass_logs(output: &Path) -> Result<()> {
+    let (dir, name) = pass_log(output);
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_name().to_string_lossy().starts_with(&name) {
+            std::fs::remove_file(entry.path())?;
+        }
+    }
+    Ok(())
+}
 
-    let (chart, ..) = GameScene::load_chart(fs.deref_mut(), &info)
-        .await
-        .with_context(|| tl!("load-chart-failed"))?;
-    macro_rules! ld {
-            ($path:literal) => {
-                AudioClip::new(load_file($path).await?)
-                    .with_context(|| tl!("load-sfx-failed", "name" => $path))?
+/// Parses ffmpeg bitrates like `6000k` or `12M` into bits per second.
+fn parse_bitrate(value: &str) -> Option<f64> {
+    let value = value.trim();
+    let (number, scale) = match value.char_indices().last()? {
+        (i, 'k' | 'K') => (&value[..i], 1e3),
+        (i, 'm' | 'M') => (&value[..i], 1e6),
+        _ => (value, 1.),
+    };
+    number.parse::<f64>().ok().filter(|it| *it > 0.).map(|it| it * scale)
+}
+
+/// Rough bitrate of the audio track in bits per second, ffmpeg's defaults for lossy codecs.
+fn estimated_audio_bitrate(config: &RenderConfig) -> f64 {
+    let samples = config.target_audio as f64 * 2.;
+    if let Some(bit) = config.audio_bit {
+        return samples * bit as f64;
+    }
+    match config.audio_format.to_lowercase().as_str() {
+        "flac" => samples * 16. * 0.7,
+        "wav" => samples * 16.,
+        "opus" => 96e3,
+        _ => 128e3,
+    }
+}
+
+/// Rate control arguments for `encoder`, `duration` is the length of the output in seconds.
+fn rate_control_args(config: &RenderConfig, encoder: &EncoderSpec, duration: f64) -> Result<Vec<String>> {
+    let bitrate = config.bitrate.trim();
+    Ok(match config.bitrate_control.as_str() {
+        "CRF" => vec![encoder.quality_flag.to_owned(), bitrate.to_owned()],
+        "VBR" => {
+            let scaled = |factor: f64| -> Result<String> {
+                let bitrate = parse_bitrate(bitrate).with_context(|| format!("invalid bitrate `{bitrate}`"))?;
+                Ok(format!("{}k", (bitrate * factor / 1e3).round()))
             };
+            let max = if config.max_bitrate.trim().is_empty() { scaled(1.5)? } else { config.max_bitrate.trim().to_owned() };
+            let buffer = if config.rate_buffer.trim().is_empty() { scaled(2.)? } else { config.rate_buffer.trim().to_owned() };
+            vec!["-b:v".to_owned(), bitrate.to_owned(), "-maxrate".to_owned(), max, "-bufsize".to_owned(), buffer]
         }
-    let music: Result<_> = async { AudioClip::new(fs.load_file(&info.music).await?) }.await;
-    let music = music.with_context(|| tl!("load-music-failed"))?;
-    let ending = ld!("ending.mp3"); //煞笔吧
-    let track_length = music.length() as f64;
-    let sfx_click = ld!("click.ogg");
-    let sfx_drag = ld!("drag.ogg");
-    let sfx_flick = ld!("flick.ogg");
+        "TargetSize" => {
+            if config.target_size <= 0. {
+                bail!("invalid target size {} MB", config.target_size);
+            }
+            // Leave some room for the container
+            let total = config.target_size * 1e6 * 8. * 0.97 / duration.max(1.);
+            let video = ((total - estimated_audio_bitrate(config)) / 1e3).max(100.).round();
+            info!("Target size {} MB: video bitrate {}k", config.target_size, video);
+            if config.two_pass() && encoder.two_pass.is_some() {
+                vec!["-b:v".to_owned(), format!("{video}k")]
+            } else {
+                // Capped instead, one pass can't hit an average
+                vec![
+                    "-b:v".to_owned(), format!("{video}k"),
+                    "-maxrate".to_owned(), format!("{video}k"),
+                    "-bufsize".to_owned(), format!("{}k", video * 2.),
+                ]
+            }
+        }
+        _ => vec!["-b:v".to_owned(), bitrate.to_owned()],
+    })
+}
 
-    //let mut gl = unsafe { get_internal_gl() };
-    let gl = unsafe { get_internal_gl() };
+/// Time cut from the start of the video, the loading screen when `disable_loading` is set.
+pub fn trim_start(config: &RenderConfig) -> f64 {
+    if config.disable_loading {
+        LoadingScene::TOTAL_TIME as f64 + GameScene::BEFORE_TIME as f64
+    } else {
+        0.1
+    }
+}
 
-    let volume_music = std::mem::take(&mut config.volume_music);
-    let volume_sfx = std::mem::take(&mut config.volume_sfx);
+/// Frames in the `index`-th of `count` equal slices of the encoded range.
+pub fn slice_range(first_frame: u64, frames: u64, index: u32, count: u32) -> Range<u64> {
+    let span = frames.saturating_sub(first_frame);
+    let at = |index: u32| first_frame + span * index as u64 / count.max(1) as u64;
+    at(index)..at(index + 1)
+}
 
-    let length = track_length - chart.offset.min(0.) as f64 + 1.;
-    let video_length = O + length + A + params.config.ending_length;
-    let offset = chart.offset.max(0.);
+/// Writes a concat demuxer list of `parts`, which must live in the same directory as `list`.
+pub fn write_concat_list(list: &Path, parts: &[PathBuf]) -> Result<()> {
+    let mut content = String::new();
+    for part in parts {
+        writeln!(&mut content, "file '{}'", part.file_name().unwrap().to_string_lossy())?;
+    }
+    std::fs::write(list, content)?;
+    Ok(())
+}
 
-    let render_start_time = Instant::now();
+/// Joins the video-only files listed in `list` and muxes in the mixed `audio` track, trimmed the
+/// same way a single-stream render would be.
+pub fn mux_video_parts(ffmpeg: &str, list: &Path, audio: &Path, config: &RenderConfig, output: &Path) -> Result<()> {
+    let (audio_codec, _) = select_audio_codec(config)?;
+    let video = match config.video {
+        true => "mov",
+        false => "mp4",
+    };
+    let mut args = Output::new().map("0:v:0").map("1:a:0").codec("v", "copy").codec("a", &audio_codec);
+    if config.audio_format == "flac" && video == "mp4" {
+        args = args.option("-strict", "-2");
+    }
+    let status = FfmpegCommand::new()
+        .input(Input::new(list).format("concat").option("-safe", "0"))
+        .input(Input::new(audio).option("-ss", trim_start(config).to_string()))
+        .output(args.format(video), output)
+        .command(ffmpeg)
+        .stderr(Stdio::inherit())
+        .status()
+        .with_context(|| tl!("run-ffmpeg-failed"))?;
+    if !status.success() {
+        bail!("ffmpeg failed to join video parts ({status})");
+    }
+    Ok(())
+}
 
-    send(IPCEvent::StartMixing);
-    let mixing_output = NamedTempFile::new()?;
-    let target_sample_rate = params.config.target_audio;
-    let sample_rate = 44100;
-    let sample_rate_f64 = sample_rate as f64;
-    assert_eq!(sample_rate, ending.sample_rate());
-    assert_eq!(sample_rate, sfx_click.sample_rate());
-    assert_eq!(sample_rate, sfx_drag.sample_rate());
-    assert_eq!(sample_rate, sfx_flick.sample_rate());
+/// Sidecar file next to the segments, recording which ones are fully encoded.
+#[derive(Serialize, Deserialize, Default)]
+struct SegmentManifest {
+    /// Hash of everything that affects the encoded frames, segments from a render with different
+    /// settings are discarded.
+    fingerprint: u64,
+    completed: Vec<u64>,
+}
 
-    let fps_f64 = params.config.fps as f64;
-    let frame_duration = 1.0 / fps_f64;
-    let audio_delay = params.config.audio_delay_frames as f64 * frame_duration;
+fn segment_dir(output: &Path) -> PathBuf {
+    let mut name = output.file_name().unwrap_or_default().to_owned();
+    name.push(".parts");
+    output.with_file_name(name)
+}
+
+/// Identifies the render a set of segments belongs to. FNV-1a, since the std hashers may change
+/// between Rust releases and the manifest outlives the binary that wrote it.
+fn segment_fingerprint(config: &RenderConfig, chart: &Path, frames: u64, encoder: &str) -> u64 {
+    let config = serde_json::to_string(config).unwrap_or_default();
+    let chart = chart.to_string_lossy();
+    let frames = frames.to_le_bytes();
+    let mut hash = 0xcbf29ce484222325u64;
+    // Lengths are hashed too, so that a byte can't move from one field to the next
+    for field in [config.as_bytes(), chart.as_bytes(), &frames, encoder.as_bytes()] {
+        for byte in (field.len() as u64).to_le_bytes().iter().chain(field) {
+            hash = (hash ^ *byte as u64).wrapping_mul(0x100000001b3);
+        }
+    }
+    hash
+}
+
+/// Encodes fixed-length chunks of frames into separate video-only files, concatenated with
+/// ffmpeg's concat demuxer once all of them exist.
+struct SegmentedOutput {
+    dir: PathBuf,
+    manifest: SegmentManifest,
+    ffmpeg: String,
+    /// Command taking rawvideo on stdin, `output` is added for each segment.
+    command: FfmpegCommand,
+    output: Output,
+    first_frame: u64,
+    segment_frames: u64,
+    current: Option<(u64, Child, ChildStdin)>,
+}
+
+impl SegmentedOutput {
+    fn open(
+        dir: PathBuf,
+        fingerprint: u64,
+        ffmpeg: String,
+        (command, output): (FfmpegCommand, Output),
+        first_frame: u64,
+        segment_frames: u64,
+    ) -> Result<Self> {
+        let manifest_path = dir.join("manifest.json");
+        let manifest = std::fs::read(&manifest_path)
+            .ok()
+            .and_then(|it| serde_json::from_slice::<SegmentManifest>(&it).ok())
+            .filter(|it| it.fingerprint == fingerprint);
+        let manifest = match manifest {
+            Some(manifest) => {
+                info!("Found {} completed segments in {}", manifest.completed.len(), dir.display());
+                manifest
+            }
+            None => {
+                if dir.exists() {
+                    std::fs::remove_dir_all(&dir)?;
+                }
+                std::fs::create_dir_all(&dir)?;
+                SegmentManifest {
+                    fingerprint,
+                    completed: Vec::new(),
+                }
+            }
+        };
+        Ok(Self {
+            dir,
+            manifest,
+            ffmpeg,
+            command,
+            output,
+            first_frame,
+            segment_frames,
+            current: None,
+        })
+    }
+
+    fn segment_of(&self, frame: u64) -> u64 {
+        (frame - self.first_frame) / self.segment_frames
+    }
+
+    fn segment_path(&self, segment: u64) -> PathBuf {
+        self.dir.join(format!("segment_{segment:05}.mkv"))
+    }
+
+    fn wants(&self, frame: u64) -> bool {
+        frame >= self.first_frame && !self.manifest.completed.contains(&self.segment_of(frame))
+    }
+
+    /// First frame of the first segment that isn't completed yet.
+    fn start(&self) -> u64 {
+        let segment = (0..).find(|it| !self.manifest.completed.contains(it)).unwrap();
+        self.first_frame + segment * self.segment_frames
+    }
 
-    info!("=== Audio/Video Sync Configuration ===");
-    info!("  Audio delay: {} frames", params.config.audio_delay_frames);
-    info!("  Audio delay: {:.6} seconds", audio_delay);
-    info!("  Frame duration: {:.6}s @ {}fps", frame_duration, params.config.fps);
-    info!("  Sample delay: {} samples @ {}Hz", (audio_delay * sample_rate_f64).round() as i64, sample_rate);
-    info!("======================================");
+    fn write_frame(&mut self, frame: u64, data: &[u8]) -> Result<()> {
+        let segment = self.segment_of(frame);
+        if !matches!(&self.current, Some((current, ..)) if *current == segment) {
+            self.close_current()?;
+            let command = self.command.clone().output(self.output.clone(), self.segment_path(segment));
+            let (proc, input) = spawn_encoder(&self.ffmpeg, &command)?;
+            self.current = Some((segment, proc, input));
+        }
+        let (_, _, input) = self.current.as_mut().unwrap();
+        input.write_all(data)?;
+        Ok(())
+    }
 
-    let audio_buffer_length = video_length + audio_delay.abs();
-    let mut output = vec![0.0_f32; (audio_buffer_length * sample_rate_f64).ceil() as usize * 2];
+    fn close_current(&mut self) -> Result<()> {
+        let Some((segment, mut proc, input)) = self.current.take() else {
+            return Ok(());
+        };
+        drop(input);
+        let status = proc.wait()?;
+        if !status.success() {
+            bail!("ffmpeg failed to encode segment {segment} ({status})");
+        }
+        self.manifest.completed.push(segment);
+        std::fs::write(self.dir.join("manifest.json"), serde_json::to_vec(&self.manifest)?)?;
+        Ok(())
+    }
 
-    if volume_music != 0.0 {
-        let start_time = Instant::now();
-        let original_pos = O - chart.offset.min(0.) as f64;
-        let pos = original_pos + audio_delay;
+    /// Closes the last segment and writes the concat demuxer list, returning its path.
+    fn finish(mut self) -> Result<PathBuf> {
+        self.close_current()?;
+        let mut segments = self.manifest.completed.clone();
+        segments.sort_unstable();
+        let segments: Vec<_> = segments.into_iter().map(|it| self.segment_path(it)).collect();
+        let path = self.dir.join("segments.txt");
+        write_concat_list(&path, &segments)?;
+        Ok(path)
+    }
+}
 
-        info!("Music mixing: original_pos={:.6}s, delayed_pos={:.6}s", original_pos, pos);
+/// How the RGB frames are converted to YUV and how the result is tagged, so that players don't
+/// have to guess the matrix and range.
+pub struct ColorSpec {
+    /// `colorspace` tag, also the matrix of the conversion.
+    matrix: &'static str,
+    primaries: &'static str,
+    transfer: &'static str,
+    full_range: bool,
+}
 
-        //let count = (music.length() as f64 * sample_rate_f64) as usize;
-        let start_index = (pos * sample_rate_f64).round() as usize * 2;
-        let ratio = 1.0 / sample_rate_f64;
+impl ColorSpec {
+    pub fn of(config: &RenderConfig) -> Result<Self> {
+        let full_range = match config.color_range.as_str() {
+            "limited" => false,
+            "full" => true,
+            other => bail!("Unsupported color range: {}. Supported ranges are: limited, full", other),
+        };
+        if config.hdr {
+            return Ok(Self { matrix: "bt2020nc", primaries: "bt2020", transfer: "smpte2084", full_range });
+        }
+        let (matrix, primaries, transfer) = match config.color_space.as_str() {
+            "bt709" => ("bt709", "bt709", "bt709"),
+            "bt601" => ("smpte170m", "smpte170m", "smpte170m"),
+            other => bail!("Unsupported color space: {}. Supported color spaces are: bt709, bt601", other),
+        };
+        Ok(Self { matrix, primaries, transfer, full_range })
+    }
 
-        if start_index >= output.len() {
-            warn!("Music start position {} exceeds output buffer length {}", start_index, output.len());
+    /// Filter put in front of the encoder's, the `format` filter after it picks the output
+    /// format so the conversion happens here with the explicit matrix.
+    pub fn scale_filter(&self) -> String {
+        let range = if self.full_range { "full" } else { "limited" };
+        if self.transfer == "smpte2084" {
+            // sRGB white lands on the 203 nit reference white of BT.2408
+            format!("zscale=tin=iec61966-2-1:pin=709:t=smpte2084:p=2020:m=2020_ncl:npl=203:r={range}")
         } else {
-            let output_ptr = output.as_mut_ptr();
-            let max_i = (output.len() - start_index) / 2;
-            let effective_count = ((music.length() as f64 * sample_rate_f64) as usize).min(max_i);
-            let mut time = 0.0_f64;
-            for i in 0..effective_count {
-                let frame = music.sample(time as f32).unwrap_or_default();
-                let left = frame.0 * volume_music;
-                let right = frame.1 * volume_music;
+            let matrix = if self.matrix == "smpte170m" { "bt601" } else { self.matrix };
+            format!("scale=out_color_matrix={matrix}:out_range={range}")
+        }
+    }
 
-                unsafe {
-                    let idx = start_index + i * 2;
-                    *output_ptr.add(idx) += left;
-                    *output_ptr.add(idx + 1) += right;
-                }
-                time += ratio;
+    pub fn tags(&self) -> [&'static str; 8] {
+        [
+            "-colorspace", self.matrix,
+            "-color_primaries", self.primaries,
+            "-color_trc", self.transfer,
+            "-color_range", if self.full_range { "pc" } else { "tv" },
+        ]
+    }
+}
+
+/// Checks that `config.video_codec` can carry `config.bit_depth` and `config.hdr`.
+fn check_bit_depth(config: &RenderConfig) -> Result<()> {
+    match config.bit_depth {
+        8 => {
+            if config.hdr {
+                bail!("HDR output requires a bit depth of 10");
+            }
+        }
+        10 => {
+            if !matches!(config.video_codec.as_str(), "hevc" | "av1") {
+                bail!("10-bit output requires HEVC or AV1, not {}", config.video_codec);
             }
         }
-        info!("music Time:{:?}", start_time.elapsed());
+        other => bail!("Unsupported bit depth: {}. Supported depths are: 8, 10", other),
+    }
+    Ok(())
+}
+
+/// Audio-only renders never run the video encoder the extra arguments are meant for.
+fn check_extra_args(config: &RenderConfig) -> Result<()> {
+    let extras = [&config.extra_input_args, &config.extra_output_args, &config.extra_video_filters];
+    if config.audio_only() && extras.iter().any(|it| !it.trim().is_empty()) {
+        bail!("Extra ffmpeg arguments and filters only apply to video output, not audio-only renders");
+    }
+    Ok(())
+}
+
+/// `user` output arguments followed by the `pass` ones of a two-pass encode. ffmpeg only keeps the
+/// last `-x265-params`, so one given by the user is merged with the pass settings instead.
+fn with_pass_args(user: &[String], pass: Vec<String>) -> Vec<String> {
+    let mut args = user.to_vec();
+    for pair in pass.chunks(2) {
+        let [flag, value] = pair else { unreachable!() };
+        match args.iter().position(|it| it == flag) {
+            Some(i) if flag == "-x265-params" && i + 1 < args.len() => args[i + 1] = format!("{}:{value}", args[i + 1]),
+            _ => args.extend([flag.clone(), value.clone()]),
+        }
     }
+    args
+}
 
-    let mut place = |pos: f64, clip: &AudioClip, volume: f32| {
-        let position = (pos * sample_rate_f64).round() as usize * 2;
-        if position >= output.len() {
-            return 0;
+/// Reallocates the color buffers of `mst` as half floats, so that the scene is drawn, blended
+/// and resolved at more than 8 bits per channel. The buffers keep their objects and sample counts,
+/// only their storage changes.
+unsafe fn use_half_float(mst: &MSRenderTarget, width: u32, height: u32) -> Result<()> {
+    use miniquad::gl::*;
+    const GL_RGBA16F: GLenum = 0x881A;
+    const GL_HALF_FLOAT: GLenum = 0x140B;
+    const GL_ATTACHMENT_OBJECT_TYPE: GLenum = 0x8CD0;
+    const GL_ATTACHMENT_OBJECT_NAME: GLenum = 0x8CD1;
+    const GL_RENDERBUFFER_SAMPLES: GLenum = 0x8CAB;
+
+    let input = internal_id(&mst.input());
+    let output = internal_id(&mst.output());
+    for fbo in if input == output { vec![output] } else { vec![input, output] } {
+        glBindFramebuffer(GL_FRAMEBUFFER, fbo);
+        let (mut kind, mut name) = (0, 0);
+        glGetFramebufferAttachmentParameteriv(GL_FRAMEBUFFER, GL_COLOR_ATTACHMENT0, GL_ATTACHMENT_OBJECT_TYPE, &mut kind);
+        glGetFramebufferAttachmentParameteriv(GL_FRAMEBUFFER, GL_COLOR_ATTACHMENT0, GL_ATTACHMENT_OBJECT_NAME, &mut name);
+        if kind as GLenum == GL_RENDERBUFFER {
+            glBindRenderbuffer(GL_RENDERBUFFER, name as _);
+            let mut samples = 0;
+            glGetRenderbufferParameteriv(GL_RENDERBUFFER, GL_RENDERBUFFER_SAMPLES, &mut samples);
+            glRenderbufferStorageMultisample(GL_RENDERBUFFER, samples, GL_RGBA16F, width as _, height as _);
+            glBindRenderbuffer(GL_RENDERBUFFER, 0);
+        } else {
+            glBindTexture(GL_TEXTURE_2D, name as _);
+            glTexImage2D(
+                GL_TEXTURE_2D, 0, GL_RGBA16F as _,
+                width as _, height as _, 0,
+                GL_RGBA, GL_HALF_FLOAT,
+                std::ptr::null(),
+            );
+            glBindTexture(GL_TEXTURE_2D, 0);
         }
-        let len = clip.frame_count();
-        let output_len = output.len() - position;
-        let valid_frames = (output_len / 2).min(len);
-
-        let output_ptr = unsafe { output.as_mut_ptr().add(position) };
-        let frames_ptr = clip.frames().as_ptr();
-
-        for i in 0..valid_frames {
-            unsafe {
-                let sample = (*frames_ptr.add(i)).0 * volume;
-                *output_ptr.add(i * 2) += sample;
-                *output_ptr.add(i * 2 + 1) += sample;
-            }
+        let status = glCheckFramebufferStatus(GL_FRAMEBUFFER);
+        glBindFramebuffer(GL_FRAMEBUFFER, 0);
+        if status != GL_FRAMEBUFFER_COMPLETE {
+            bail!("the GPU can't render to half float buffers (framebuffer status {status:#x}), which 10-bit output needs");
         }
-        valid_frames
-    };
+    }
+    Ok(())
+}
+
+/// Makes alpha blending accumulate coverage (`src_a + dst_a * (1 - src_a)`) instead of applying
+/// the color factors to alpha as well, which leaves `src_a²` on a transparent target and makes
+/// anything semi-transparent come out too faint.
+///
+/// miniquad only touches the blend state when a pipeline needs different blending than it last
+/// set, so the state is first set through it with the color factors of macroquad's default
+/// pipeline. The separate alpha factors then stay until a pipeline with other blending is applied.
+unsafe fn blend_alpha_separately() {
+    use miniquad::{gl::*, BlendFactor, BlendState, BlendValue, Equation};
+    let color = BlendState::new(
+        Equation::Add,
+        BlendFactor::Value(BlendValue::SourceAlpha),
+        BlendFactor::OneMinusValue(BlendValue::SourceAlpha),
+    );
+    get_internal_gl().quad_context.set_blend(Some(color), None);
+    glBlendFuncSeparate(GL_SRC_ALPHA, GL_ONE_MINUS_SRC_ALPHA, GL_ONE, GL_ONE_MINUS_SRC_ALPHA);
+}
 
-    if volume_sfx != 0.0 {
-        let start_time = Instant::now();
+/// Returns the ffmpeg encoder arguments for `config.image_format`.
+fn image_codec_args(config: &RenderConfig) -> Result<&'static [&'static str]> {
+    Ok(match config.image_format.to_lowercase().as_str() {
+        "png" => &["-c:v", "png"],
+        "exr" => &["-c:v", "exr"],
+        "webp" => &["-c:v", "libwebp", "-lossless", "1"],
+        other => bail!("Unsupported image format: {}. Supported formats are: png, exr, webp", other),
+    })
+}
 
-        let offset_f64 = offset as f64;
-        let o_offset = O + offset_f64 + audio_delay;
+/// Returns the ffmpeg video encoder arguments, pixel format and audio encoder for
+/// `config.alpha_codec`.
+fn alpha_codec_args(config: &RenderConfig) -> Result<(&'static [&'static str], &'static str, &'static str)> {
+    Ok(match config.alpha_codec.as_str() {
+        "prores" => (&["-c:v", "prores_ks", "-profile:v", "4444", "-alpha_bits", "16"], "yuva444p10le", "pcm_s24le"),
+        "qtrle" => (&["-c:v", "qtrle"], "argb", "pcm_s24le"),
+        // WebM only takes Opus or Vorbis
+        "vp9" => (&["-c:v", "libvpx-vp9", "-b:v", "0", "-crf", "15", "-row-mt", "1"], "yuva420p", "libopus"),
+        other => bail!("Unsupported alpha codec: {}. Supported codecs are: prores, qtrle, vp9", other),
+    })
+}
 
-        info!("SFX mixing: offset={:.6}s (includes {:.6}s delay)", o_offset, audio_delay);
+/// Serves a fully transparent image in place of the chart illustration so that transparent renders
+/// keep nothing but the notes, lines and UI.
+struct TransparentIllustration {
+    inner: Box<dyn FileSystem>,
+    illustration: String,
+}
 
-        let sfx_lut =
-        [
-            &sfx_click as *const _,
-            &sfx_drag as *const _,
-            &sfx_click as *const _,
-            &sfx_flick as *const _,
-        ];
+impl TransparentIllustration {
+    /// 1x1 RGBA PNG with zero alpha.
+    const IMAGE: &'static [u8] = &[
+        0x89, 0x50, 0x4e, 0x47, 0x0d, 0x0a, 0x1a, 0x0a, 0x00, 0x00, 0x00, 0x0d, 0x49, 0x48, 0x44, 0x52, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00,
+        0x00, 0x01, 0x08, 0x06, 0x00, 0x00, 0x00, 0x1f, 0x15, 0xc4, 0x89, 0x00, 0x00, 0x00, 0x0b, 0x49, 0x44, 0x41, 0x54, 0x78, 0x9c, 0x63,
+        0x60, 0x00, 0x02, 0x00, 0x00, 0x05, 0x00, 0x01, 0x7a, 0x5e, 0xab, 0x3f, 0x00, 0x00, 0x00, 0x00, 0x49, 0x45, 0x4e, 0x44, 0xae, 0x42,
+        0x60, 0x82,
+    ];
+}
 
-        unsafe {
-            let lines_ptr = chart.lines.as_ptr();
-            let lines_len = chart.lines.len();
-
-            for i in 0..lines_len {
-                let line = &*lines_ptr.add(i);
-                let notes_ptr = line.notes.as_ptr();
-                let notes_len = line.notes.len();
-                for j in 0..notes_len {
-                    let note = &*notes_ptr.add(j);
-                    if !note.fake {
-                        let sfx = &*sfx_lut[note.kind.order() as usize];
-                        let time = o_offset + note.time as f64;
-                        place(time, sfx, volume_sfx);
-                    }
-                }
-            }
+#[async_trait]
+impl FileSystem for TransparentIllustration {
+    async fn load_file(&mut self, path: &str) -> Result<Vec<u8>> {
+        if path == self.illustration {
+            return Ok(Self::IMAGE.to_vec());
         }
+        self.inner.load_file(path).await
+    }
+
+    async fn exists(&mut self, path: &str) -> Result<bool> {
+        self.inner.exists(path).await
+    }
 
-        info!("sfx Time:{:?}", start_time.elapsed());
+    fn list_root(&self) -> Result<Vec<String>> {
+        self.inner.list_root()
     }
 
-    let mut pos = O + length + A + audio_delay;
-    info!("Ending music start: {:.6}s", pos);
+    fn clone_box(&self) -> Box<dyn FileSystem> {
+        Box::new(Self {
+            inner: self.inner.clone_box(),
+            illustration: self.illustration.clone(),
+        })
+    }
 
-    while place(pos, &ending, volume_music) != 0 && params.config.ending_length > 0.1 {
-        pos += ending.frame_count() as f64 / sample_rate_f64;
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
     }
+}
 
-    let audio_bit = params.config.audio_bit;
-    let audio_format = params.config.audio_format.to_lowercase();
+/// Returns the ffmpeg audio codec and container format for `config.audio_format`.
+pub fn select_audio_codec(config: &RenderConfig) -> Result<(String, String)> {
+    let audio_bit = config.audio_bit;
+    let audio_format = config.audio_format.to_lowercase();
 
     let supported_formats = ["flac", "mp3", "aac", "opus", "wav"];
     if !supported_formats.contains(&audio_format.as_str()) {
@@ -662,7 +1195,7 @@ pub async fn main() -> Result<()> {
         { return Err(anyhow::anyhow!("PCM audio bit depth requires WAV format, but {} was specified", audio_format)); }
     }
 
-    let (audio_codec, output_format) = if let Some(bit) = audio_bit {
+    Ok(if let Some(bit) = audio_bit {
         (format!("pcm_f{}le", bit), "wav".to_string())
     } else {
         match audio_format.as_str()
@@ -677,61 +1210,427 @@ pub async fn main() -> Result<()> {
                 ("aac".to_string(), "mp4".to_string())
             }
         }
-    };
+    })
+}
 
-    // 构建参数字符串
-    let args_str = if target_sample_rate != sample_rate {
-        let resample_filter = format!("aresample=resampler=soxr:precision=33:osr={}:dither_method=triangular", target_sample_rate);
-        format!(
-            "-y -f f32le -ar {} -ac 2 -i - -af {} -c:a {} -f {}",
-            sample_rate,
-            resample_filter,
-            audio_codec,
-            output_format
-        )
-    } else {
-        format!(
-            "-y -f f32le -ar {} -ac 2 -i - -c:a {} -f {}",
-            sample_rate,
-            audio_codec,
-            output_format
-        )
-    };
+pub async fn main() -> Result<()> {
+    use crate::ipc::{client::send, PROTOCOL_VERSION};
 
-    let mut proc = cmd_hidden(&ffmpeg)
-        .args(args_str.split_whitespace())
-        .arg(mixing_output.path())
-        .arg("-loglevel")
-        .arg("warning")
-        .stdin(Stdio::piped())
-        .stderr(Stdio::inherit())
-        .spawn()
-        .with_context(|| tl!("run-ffmpeg-failed"))?;
-    let input = proc.stdin.as_mut().unwrap();
-    let mut writer = BufWriter::new(input);
-    for sample in output.into_iter() {
-        writer.write_all(&sample.to_le_bytes())?;
-    }
-    drop(writer);
-    proc.wait()?;
+    send(IPCEvent::Hello { version: PROTOCOL_VERSION });
+    set_pc_assets_folder(&std::env::args().nth(2).unwrap());
 
-    //let (vw, vh) = params.config.resolution;
+    let result = async {
+        let mut stdin = std::io::stdin().lock();
+        let stdin = &mut stdin;
 
-    let target_aspect = info.aspect_ratio as f64;
-    let (mut vw, mut vh) = params.config.resolution;
-    let (ow, oh) = (vw, vh);
-    let aspect = vw as f64 / vh as f64;
+        let mut line = String::new();
+        stdin.read_line(&mut line)?;
+        let params: RenderParams = serde_json::from_str(line.trim())?;
 
-    if (aspect - target_aspect).abs() > 1e-9 {
-        if aspect > target_aspect {
-            vw = (vh as f64 * target_aspect).round() as u32;
-        } else {
-            vh = (vw as f64 / target_aspect).round() as u32;
-        }
-        info!("{}x{} -> {}x{} (target {:.9})", ow, oh, vw, vh, target_aspect);
+        line.clear();
+        stdin.read_line(&mut line)?;
+        let output_path: PathBuf = serde_json::from_str(line.trim())?;
+
+        render(params, output_path).await
     }
+    .await;
+    if let Err(err) = &result {
+        let kind = ErrorKind::of(err);
+        send(IPCEvent::Error {
+            kind,
+            message: err.to_string(),
+            context_chain: err.chain().skip(1).map(ToString::to_string).collect(),
+            hint: kind.hint(),
+        });
+    }
+    result
+}
+
+/// Picks the encoder for `config` from the `probe` and reports it to the task, failing if
+/// hardware acceleration is on and no hardware encoder works.
+fn select_encoder(config: &RenderConfig, probe: EncoderProbe) -> Result<&'static EncoderSpec> {
+    use crate::ipc::client::send;
+
+    let hw_detected = probe.detected;
+    let mut encoder_availability = probe.available;
+    if !config.hardware_accel {
+        encoder_availability.retain(|name| encoder::find_encoder(name).is_some_and(|it| !it.is_hardware()));
+    }
+    let codec_prefix = format!("{}_", config.video_codec);
+    let hw_errors: Vec<String> = probe
+        .errors
+        .into_iter()
+        .filter(|(name, _)| config.hardware_accel && name.starts_with(&codec_prefix))
+        .map(|(_, error)| error)
+        .collect();
+
+    let candidates = encoder::candidates(&config.video_codec, &config.encoder);
+    // The last candidate is a software encoder that is used even if ffmpeg didn't list it
+    let encoder = *candidates
+        .iter()
+        .find(|it| encoder_availability.contains(it.name))
+        .or(candidates.last())
+        .expect("At least one software encoder is available.");
+    let ffmpeg_encoder = encoder.name;
+
+    let mut selection_log = format!(
+        "=== Encoder Selection ===\n\
+         Video codec: {}\n\
+         User preference: {}\n\
+         --- Encoder Availability ---",
+        config.video_codec, config.encoder,
+    );
+    for it in ENCODERS.iter().filter(|it| it.is_hardware()) {
+        write!(&mut selection_log, "\n  {}: {}", it.name, encoder_availability.contains(it.name))?;
+    }
+    info!("{selection_log}");
+    if !hw_errors.is_empty() {
+        info!("  --- Encoder Errors ---");
+        for error in &hw_errors {
+            info!("    {}", error);
+        }
+    }
+    info!("  Selected encoder: {}", ffmpeg_encoder);
+    info!("=========================");
+    for error in &hw_errors {
+        send(IPCEvent::Warning(error.clone()));
+    }
+    send(IPCEvent::Encoder(ffmpeg_encoder.to_owned()));
+
+    // Regardless of the preference, like the software fallback above
+    let hardware_available = encoder::candidates(&config.video_codec, "auto")
+        .iter()
+        .any(|it| it.is_hardware() && encoder_availability.contains(it.name));
+    if config.hardware_accel && !hardware_available {
+        let mut detailed_error = String::new();
+        detailed_error += &format!("{}\n", tl!("no-hwacc"));
+
+        detailed_error += "Hardware detection summary:\n";
+        for vendor in Vendor::HARDWARE {
+            let detected = ENCODERS.iter().any(|it| it.vendor == vendor && hw_detected.contains(it.name));
+            detailed_error += &format!(" - {}: {}\n", vendor.label(), detected);
+        }
+        detailed_error += "\n";
+
+        detailed_error += "Encoder test results:\n";
+        for name in ENCODERS.iter().filter(|it| it.is_hardware()).map(|it| it.name).chain(hw_detected.iter().filter(|it| it.ends_with("_cuvid"))) {
+            detailed_error += &format!(
+                "- {}: {}\n",
+                name,
+                if encoder_availability.contains(name) { "SUCCESS" } else { "FAILED" }
+            );
+        }
+        detailed_error += "\n";
+
+        // 详细的错误日志
+        if !hw_errors.is_empty() {
+            detailed_error += "Detailed error logs:\n";
+            for (i, error) in hw_errors.iter().enumerate() {
+                detailed_error += &format!("{}. {}\n", i + 1, error);
+            }
+            detailed_error += "\n";
+        } else {
+            detailed_error += "No hardware encoders were tested (all detection failed).\n\n";
+        }
+
+        return Err(anyhow::anyhow!(detailed_error).context(ErrorKind::EncoderUnavailable));
+    }
+    Ok(encoder)
+}
+
+pub async fn render(params: RenderParams, output_path: PathBuf) -> Result<()> {
+    use crate::ipc::client::*;
+
+    // Two-pass encodes run ffmpeg in the output directory, where a relative path would point
+    // somewhere else
+    let output_path = std::path::absolute(output_path)?;
+    let path = params.path;
+    let mut fs = fs::fs_from_file(&path).context(ErrorKind::ChartLoad)?;
+
+    let font = FontArc::try_from_vec(load_file("font.ttf").await?)?;
+
+    let Some(ffmpeg) = find_ffmpeg()? else {
+        bail!(ErrorKind::FfmpegMissing)
+    };
+    info!("Using ffmpeg: {}", ffmpeg);
+
+    let mut painter = TextPainter::new(font);
+
+    let mut config = params.config.to_config();
+    config.mods = Mods::AUTOPLAY;
+
+    let mut info = params.info;
+
+    let (mut chart, ..) = GameScene::load_chart(fs.deref_mut(), &info)
+        .await
+        .context(ErrorKind::ChartLoad)?;
+    macro_rules! ld {
+            ($path:literal) => {
+                AudioClip::new(load_file($path).await?)
+                    .with_context(|| tl!("load-sfx-failed", "name" => $path))?
+            };
+        }
+    let music: Result<_> = async { AudioClip::new(fs.load_file(&info.music).await?) }.await;
+    let music = music.with_context(|| tl!("load-music-failed")).context(ErrorKind::ChartLoad)?;
+    let ending = ld!("ending.mp3"); //煞笔吧
+    let track_length = music.length() as f64;
+
+    //let mut gl = unsafe { get_internal_gl() };
+    let gl = unsafe { get_internal_gl() };
+
+    let volume_music = std::mem::take(&mut config.volume_music);
+    let volume_sfx = std::mem::take(&mut config.volume_sfx);
+
+    let length = track_length - chart.offset.min(0.) as f64 + 1.;
+    let video_length = O + length + A + params.config.ending_length;
+    let offset = chart.offset.max(0.);
+
+    let render_start_time = Instant::now();
+
+    let part = params.part;
+    let audio_only = params.config.audio_only();
+    let image_sequence = params.config.image_sequence();
+    let (audio_codec, output_format) = if image_sequence {
+        // Editors get an uncompressed track next to the frames regardless of `audio_format`
+        ("pcm_s24le".to_owned(), "wav".to_owned())
+    } else {
+        select_audio_codec(&params.config).context(ErrorKind::InvalidConfig)?
+    };
+    let color = ColorSpec::of(&params.config).context(ErrorKind::InvalidConfig)?;
+    // Frames and transparent videos keep the deeper readback in their own formats
+    if params.config.can_slice() {
+        check_bit_depth(&params.config).context(ErrorKind::InvalidConfig)?;
+    }
+    check_extra_args(&params.config).context(ErrorKind::InvalidConfig)?;
+    let mixing_output = NamedTempFile::new()?;
+    // Slices only carry video, the audio is mixed once by the `Audio` part
+    if !matches!(part, RenderPart::Slice { .. } | RenderPart::Analysis) {
+        send(IPCEvent::StartMixing);
+        let target_sample_rate = params.config.target_audio;
+        let sample_rate = params.config.mixing_rate();
+        let sample_rate_f64 = sample_rate as f64;
+        let resample_start = Instant::now();
+        let ending = Track::of(&ending, sample_rate);
+        // Broken custom hitsounds of the chart are skipped, what fails here are the hitsound settings
+        let mut hitsounds = Hitsounds::load(&params.config, fs.deref_mut(), &info, sample_rate)
+            .await
+            .context(ErrorKind::InvalidConfig)?;
+        info!("Mixing at {}Hz, resampled clips in {:?}", sample_rate, resample_start.elapsed());
+
+        let fps_f64 = params.config.fps as f64;
+        let frame_duration = 1.0 / fps_f64;
+        let audio_delay = params.config.audio_delay_frames as f64 * frame_duration;
+
+        info!("=== Audio/Video Sync Configuration ===");
+        info!("  Audio delay: {} frames", params.config.audio_delay_frames);
+        info!("  Audio delay: {:.6} seconds", audio_delay);
+        info!("  Frame duration: {:.6}s @ {}fps", frame_duration, params.config.fps);
+        info!("  Sample delay: {} samples @ {}Hz", (audio_delay * sample_rate_f64).round() as i64, sample_rate);
+        info!("======================================");
+
+        let audio_buffer_length = video_length + audio_delay.abs();
+        let mut output = vec![0.0_f32; (audio_buffer_length * sample_rate_f64).ceil() as usize * 2];
+
+        if volume_music != 0.0 {
+            let start_time = Instant::now();
+            let original_pos = O - chart.offset.min(0.) as f64;
+            let pos = original_pos + audio_delay;
+
+            info!("Music mixing: original_pos={:.6}s, delayed_pos={:.6}s", original_pos, pos);
+
+            let start_index = (pos * sample_rate_f64).round() as usize * 2;
+            let music = Track::of(&music, sample_rate);
+
+            if start_index >= output.len() {
+                warn!("Music start position {} exceeds output buffer length {}", start_index, output.len());
+                send(IPCEvent::Warning(format!("Music starts after the end of the video ({:.2}s)", pos)));
+            } else {
+                let output_ptr = output.as_mut_ptr();
+                let max_i = (output.len() - start_index) / 2;
+                let effective_count = music.frames.len().min(max_i);
+                for (i, frame) in music.frames[..effective_count].iter().enumerate() {
+                    let left = frame[0] * volume_music;
+                    let right = frame[1] * volume_music;
+
+                    unsafe {
+                        let idx = start_index + i * 2;
+                        *output_ptr.add(idx) += left;
+                        *output_ptr.add(idx + 1) += right;
+                    }
+                }
+            }
+            info!("music Time:{:?}", start_time.elapsed());
+        }
+
+        // `pan` from -1 (left) to 1 (right), stereo clips keep their own image
+        let mut place = |pos: f64, clip: &Track, volume: f32, pan: f32| {
+            let position = (pos * sample_rate_f64).round() as usize * 2;
+            if position >= output.len() {
+                return 0;
+            }
+            let len = clip.frames.len();
+            let output_len = output.len() - position;
+            let valid_frames = (output_len / 2).min(len);
+
+            let output_ptr = unsafe { output.as_mut_ptr().add(position) };
+            let frames_ptr = clip.frames.as_ptr();
+            let (left, right) = pan_gains(pan);
+            let (left, right) = (left * volume, right * volume);
+
+            for i in 0..valid_frames {
+                unsafe {
+                    let frame = *frames_ptr.add(i);
+                    *output_ptr.add(i * 2) += frame[0] * left;
+                    *output_ptr.add(i * 2 + 1) += frame[1] * right;
+                }
+            }
+            valid_frames
+        };
+
+        if volume_sfx != 0.0 {
+            let start_time = Instant::now();
+
+            let offset_f64 = offset as f64;
+            let o_offset = O + offset_f64 + audio_delay;
+
+            info!("SFX mixing: offset={:.6}s (includes {:.6}s delay)", o_offset, audio_delay);
+
+            let pan_width = match (params.config.positional_hitsound, params.config.flid_x) {
+                (false, _) => 0.,
+                // Mirrored charts are hit on the other side
+                (true, false) => params.config.hitsound_pan,
+                (true, true) => -params.config.hitsound_pan,
+            };
+            // Worked out up front, following the lines needs them mutably
+            let positions = if pan_width != 0. {
+                hit_positions(&mut chart.lines, info.aspect_ratio)
+            } else {
+                Vec::new()
+            };
+            for (i, line) in chart.lines.iter().enumerate() {
+                for (j, note) in line.notes.iter().enumerate() {
+                    if !note.fake {
+                        let (sfx, multiplier) = hitsounds.of(i, note);
+                        let time = o_offset + note.time as f64;
+                        let pan = positions.get(i).map_or(0., |line| line[j] * pan_width);
+                        place(time, sfx, volume_sfx * multiplier, pan);
+                        if let NoteKind::Hold { end_time, .. } = note.kind {
+                            if let Some(sustain) = hitsounds.sustain((end_time - note.time) as f64) {
+                                place(time, &sustain, volume_sfx * multiplier, pan);
+                            }
+                            if let Some(release) = hitsounds.release() {
+                                place(o_offset + end_time as f64, release, volume_sfx * multiplier, pan);
+                            }
+                        }
+                    }
+                }
+            }
+
+            info!("sfx Time:{:?}", start_time.elapsed());
+        }
+
+        let mut pos = O + length + A + audio_delay;
+        info!("Ending music start: {:.6}s", pos);
+
+        while place(pos, &ending, volume_music, 0.) != 0 && params.config.ending_length > 0.1 {
+            pos += ending.duration();
+        }
+
+        let mut gain = 0.;
+        if params.config.loudness_normalize {
+            match integrated_loudness(&output, sample_rate) {
+                Some(loudness) => {
+                    gain = params.config.target_lufs - loudness;
+                    info!("Normalizing {:.1} LUFS to {:.1} LUFS", loudness, params.config.target_lufs);
+                    let factor = 10f32.powf(gain as f32 / 20.);
+                    output.iter_mut().for_each(|it| *it *= factor);
+                }
+                None => warn!("The mix is silent, skipping loudness normalization"),
+            }
+        }
+        let limited = params.config.limiter && limit(&mut output, sample_rate, 10f32.powf(params.config.limiter_ceiling as f32 / 20.));
+        let report = LoudnessReport::measure(&output, sample_rate, gain, limited);
+        info!(
+            "Mix loudness: {:.1} LUFS, true peak {:.1} dBTP{}",
+            report.integrated.unwrap_or(f64::NEG_INFINITY),
+            report.true_peak.unwrap_or(f64::NEG_INFINITY),
+            if limited { ", limited" } else { "" }
+        );
+        send(IPCEvent::Loudness(report));
+
+        let mut args = Output::new();
+        if target_sample_rate != sample_rate {
+            args = args.filter("a", format!("aresample=resampler=soxr:precision=33:osr={}:dither_method=triangular", target_sample_rate));
+        }
+        let mut proc = FfmpegCommand::new()
+            .log_level("warning")
+            .input(Input::raw_audio(sample_rate))
+            .output(args.codec("a", &audio_codec).format(&output_format), mixing_output.path())
+            .command(&ffmpeg)
+            .stdin(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .spawn()
+            .with_context(|| tl!("run-ffmpeg-failed"))?;
+        // A standalone track starts where the video would, the other parts are trimmed when muxed
+        let skip = if audio_only || image_sequence {
+            (trim_start(&params.config) * sample_rate_f64).round() as usize * 2
+        } else {
+            0
+        };
+        let input = proc.stdin.as_mut().unwrap();
+        let mut writer = BufWriter::new(input);
+        for sample in output.into_iter().skip(skip) {
+            writer.write_all(&sample.to_le_bytes())?;
+        }
+        drop(writer);
+        let status = proc.wait()?;
+        if !status.success() {
+            bail!("ffmpeg failed to encode the mixed audio ({status})");
+        }
+    }
+
+    if part == RenderPart::Audio || audio_only {
+        std::fs::copy(mixing_output.path(), &output_path)?;
+        send(IPCEvent::Done(render_start_time.elapsed().as_secs_f64()));
+        return Ok(());
+    }
+    if image_sequence {
+        std::fs::create_dir_all(&output_path)?;
+        std::fs::copy(mixing_output.path(), output_path.join("audio.wav"))?;
+    }
+
+    //let (vw, vh) = params.config.resolution;
+
+    let target_aspect = info.aspect_ratio as f64;
+    let (mut vw, mut vh) = params.config.resolution;
+    let (ow, oh) = (vw, vh);
+    let aspect = vw as f64 / vh as f64;
+
+    if (aspect - target_aspect).abs() > 1e-9 {
+        if aspect > target_aspect {
+            vw = (vh as f64 * target_aspect).round() as u32;
+        } else {
+            vh = (vw as f64 / target_aspect).round() as u32;
+        }
+        info!("{}x{} -> {}x{} (target {:.9})", ow, oh, vw, vh, target_aspect);
+    }
+
+    let transparent = params.config.transparent;
+    let fs: Box<dyn FileSystem> = if transparent {
+        info.background_dim = 0.;
+        Box::new(TransparentIllustration {
+            illustration: info.illustration.clone(),
+            inner: fs,
+        })
+    } else {
+        fs
+    };
 
     let mst = Rc::new(MSRenderTarget::new((vw, vh), config.sample_count));
+    let deep = params.config.bit_depth > 8;
+    if deep {
+        unsafe { use_half_float(&mst, vw, vh) }.context(ErrorKind::InvalidConfig)?;
+    }
     let my_time: Rc<RefCell<f64>> = Rc::new(RefCell::new(0.));
     let tm = TimeManager::manual(Box::new({
         let my_time = Rc::clone(&my_time);
@@ -769,7 +1668,6 @@ pub async fn main() -> Result<()> {
     let fps = params.config.fps;
     //let frame_delta = 1. / fps as f32;
     let frames = (video_length * fps as f64).ceil() as u64;
-    send(IPCEvent::StartRender(frames));
     /*
         let codecs = String::from_utf8(
             cmd_hidden(&ffmpeg)
@@ -831,634 +1729,166 @@ pub async fn main() -> Result<()> {
         );
     */
 
-    fn test_encoder(ffmpeg: &Path, encoder: &str) -> Result<(bool, String)> {
-        let mut cmd = Command::new(ffmpeg);
-
-        // Vulkan 编码器需要特殊的初始化命令
-        if encoder.ends_with("_vulkan") {
-            // Vulkan 编码器只支持 NV12 格式，使用 hwupload 上传
-            cmd.args(&[
-                "-init_hw_device", "vulkan=vk",
-                "-f", "lavfi",
-                "-i", "testsrc=duration=0.1:size=320x240:rate=30",
-                "-filter_hw_device", "vk",
-                "-vf", "format=nv12,hwupload",
-                "-c:v", encoder,
-                "-f", "null", "-",
-            ]);
-        } else {
-            cmd.args(&[
-                "-f", "lavfi",
-                "-i", "color=c=black:s=320x240:d=0",
-                "-c:v", encoder,
-                "-f", "null", "-",
-            ]);
-        }
-
-        cmd.arg("-loglevel")
-            .arg("warning")
-            .arg("-hide_banner")
-            .stdout(Stdio::piped())
-            .stderr(Stdio::piped());
-
-        let output = cmd
-            .output()
-            .with_context(|| format!("Failed to start encoder test for {}", encoder))?;
-
-        let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
-        Ok((output.status.success(), stderr))
-    }
-
-    let hw_detected = EncoderAvailability {
-        h264_nvenc: params.config.hardware_accel && hw_detect::detect_nvidia(),
-        hevc_nvenc: params.config.hardware_accel
-            && params.config.video_codec == "hevc"
-            && hw_detect::detect_nvidia(),
-        h264_qsv: params.config.hardware_accel && hw_detect::detect_intel_qsv(),
-        hevc_qsv: params.config.hardware_accel
-            && params.config.video_codec == "hevc"
-            && hw_detect::detect_intel_qsv(),
-        h264_amf: params.config.hardware_accel && hw_detect::detect_amd(),
-        hevc_amf: params.config.hardware_accel && params.config.video_codec == "hevc" && hw_detect::detect_amd(),
-        h264_cuvid: params.config.hardware_accel && hw_detect::detect_nvidia(),
-        hevc_cuvid: params.config.hardware_accel && params.config.video_codec == "hevc" && hw_detect::detect_nvidia(),
-        av1_cuvid: params.config.hardware_accel && params.config.video_codec == "av1" && hw_detect::detect_nvidia(),
-        av1_nvenc: params.config.hardware_accel
-            && params.config.video_codec == "av1"
-            && hw_detect::detect_nvidia(),
-        av1_qsv: params.config.hardware_accel
-            && params.config.video_codec == "av1"
-            && hw_detect::detect_intel_qsv(),
-        av1_amf: params.config.hardware_accel
-            && params.config.video_codec == "av1"
-            && hw_detect::detect_amd(),
-        // Vulkan encoders - detect Vulkan runtime
-        h264_vulkan: params.config.hardware_accel && hw_detect::detect_vulkan(),
-        hevc_vulkan: params.config.hardware_accel
-            && params.config.video_codec == "hevc"
-            && hw_detect::detect_vulkan(),
-        av1_vulkan: params.config.hardware_accel
-            && params.config.video_codec == "av1"
-            && hw_detect::detect_vulkan(),
-    };
-
-    let mut hw_errors = Vec::new();
-
-    let mut encoder_availability = EncoderAvailability {
-        h264_nvenc: false,
-        hevc_nvenc: false,
-        av1_nvenc: false,
-        h264_qsv: false,
-        hevc_qsv: false,
-        av1_qsv: false,
-        h264_amf: false,
-        hevc_amf: false,
-        av1_amf: false,
-        h264_cuvid: false,
-        hevc_cuvid: false,
-        av1_cuvid: false,
-        h264_vulkan: false,
-        hevc_vulkan: false,
-        av1_vulkan: false,
-    };
-
-    let encoders_to_test = [
-        ("h264_nvenc", hw_detected.h264_nvenc, &mut encoder_availability.h264_nvenc),
-        ("hevc_nvenc", hw_detected.hevc_nvenc, &mut encoder_availability.hevc_nvenc),
-        ("av1_nvenc", hw_detected.av1_nvenc, &mut encoder_availability.av1_nvenc),
-        ("h264_qsv", hw_detected.h264_qsv, &mut encoder_availability.h264_qsv),
-        ("hevc_qsv", hw_detected.hevc_qsv, &mut encoder_availability.hevc_qsv),
-        ("av1_qsv", hw_detected.av1_qsv, &mut encoder_availability.av1_qsv),
-        ("h264_amf", hw_detected.h264_amf, &mut encoder_availability.h264_amf),
-        ("hevc_amf", hw_detected.hevc_amf, &mut encoder_availability.hevc_amf),
-        ("av1_amf", hw_detected.av1_amf, &mut encoder_availability.av1_amf),
-        // Vulkan encoders
-        ("h264_vulkan", hw_detected.h264_vulkan, &mut encoder_availability.h264_vulkan),
-        ("hevc_vulkan", hw_detected.hevc_vulkan, &mut encoder_availability.hevc_vulkan),
-        ("av1_vulkan", hw_detected.av1_vulkan, &mut encoder_availability.av1_vulkan),
-    ];
-
-    for (name, detected, availability_flag) in encoders_to_test {
-        if detected {
-            match test_encoder(ffmpeg.as_ref(), name) {
-                Ok((success, error_output)) => {
-                    *availability_flag = success;
-
-                    if !success {
-                        hw_errors.push(format!(
-                            "{} test failed:\n{}",
-                            name,
-                            error_output.trim()
-                        ));
-                    }
-                }
-                Err(e) => {
-                    *availability_flag = false;
-                    hw_errors.push(format!("{} test error: {}", name, e));
-                }
-            }
-        }
-    }
-
-    let cuvid_to_test = [
-        ("h264_cuvid", hw_detected.h264_cuvid, &mut encoder_availability.h264_cuvid),
-        ("hevc_cuvid", hw_detected.hevc_cuvid, &mut encoder_availability.hevc_cuvid),
-        ("av1_cuvid", hw_detected.av1_cuvid, &mut encoder_availability.av1_cuvid),
-    ];
-
-    for (name, detected, availability_flag) in cuvid_to_test {
-        if detected {
-            let (encoder_name, container_format) = if name == "h264_cuvid" {
-                ("libx264", "mpegts")
-            } else if name == "hevc_cuvid" {
-                ("libx265", "mpegts")
-            } else {
-                ("libaom-av1", "matroska")
-            };
-
-            let mut encode_cmd = Command::new(&ffmpeg);
-            encode_cmd.args(&[
-                "-f", "lavfi",
-                "-i", "testsrc=duration=1:size=320x240:rate=30",
-                "-vf", "format=yuv420p",  // 强制使用 yuv420p 格式
-                "-c:v", encoder_name,
-                "-t", "0.5",  // 只编码0.5秒
-                "-f", container_format,
-                "-"  // 输出到stdout
-            ])
-                .stdout(Stdio::piped())
-                .stderr(Stdio::null());
-
-            // 2. 解码测试
-            let mut decode_cmd = Command::new(&ffmpeg);
-            decode_cmd.args(&[
-                "-hwaccel", "cuvid",
-                "-hwaccel_device", "0",
-                "-c:v", name,
-                "-f", container_format,
-                "-i", "-",  // 从stdin读取
-                "-f", "null",
-                "-"  // 输出到null
-            ])
-                .stdin(Stdio::piped())
-                .stdout(Stdio::null())
-                .stderr(Stdio::piped());
-
-            let encoded = match encode_cmd.spawn() {
-                Ok(child) => child,
-                Err(e) => {
-                    *availability_flag = false;
-                    hw_errors.push(format!("{} test encode setup failed: {}", name, e));
-                    continue;
-                }
-            };
-
-            decode_cmd.stdin(encoded.stdout.unwrap());
-
-            match decode_cmd.output() {
-                Ok(output) => {
-                    *availability_flag = output.status.success();
-                    if !output.status.success() {
-                        let stderr = String::from_utf8_lossy(&output.stderr);
-                        hw_errors.push(format!(
-                            "{} decode test failed (code {}):\n{}",
-                            name,
-                            output.status.code().unwrap_or(-1),
-                            stderr.trim()
-                        ));
-                    }
-                }
-                Err(e) => {
-                    *availability_flag = false;
-                    hw_errors.push(format!("{} decode test execution failed: {}", name, e));
-                }
-            }
-        }
-    }
-    let mut dummy_flag = false;
-    let encoder_type = params.config.encoder.as_str();
-    let candidates: Vec<(&str, bool, &mut bool)> = match params.config.video_codec.as_str() {
-        "hevc" => {
-            match encoder_type {
-                "nvenc" => vec![
-                    ("hevc_nvenc", encoder_availability.hevc_nvenc, &mut encoder_availability.hevc_nvenc),
-                    ("hevc_vulkan", encoder_availability.hevc_vulkan, &mut encoder_availability.hevc_vulkan),
-                    ("hevc_qsv", encoder_availability.hevc_qsv, &mut encoder_availability.hevc_qsv),
-                    ("hevc_amf", encoder_availability.hevc_amf, &mut encoder_availability.hevc_amf),
-                    ("libx265", true, &mut dummy_flag),
-                ],
-                "qsv" => vec![
-                    ("hevc_qsv", encoder_availability.hevc_qsv, &mut encoder_availability.hevc_qsv),
-                    ("hevc_vulkan", encoder_availability.hevc_vulkan, &mut encoder_availability.hevc_vulkan),
-                    ("hevc_nvenc", encoder_availability.hevc_nvenc, &mut encoder_availability.hevc_nvenc),
-                    ("hevc_amf", encoder_availability.hevc_amf, &mut encoder_availability.hevc_amf),
-                    ("libx265", true, &mut dummy_flag),
-                ],
-                "amf" => vec![
-                    ("hevc_amf", encoder_availability.hevc_amf, &mut encoder_availability.hevc_amf),
-                    ("hevc_vulkan", encoder_availability.hevc_vulkan, &mut encoder_availability.hevc_vulkan),
-                    ("hevc_nvenc", encoder_availability.hevc_nvenc, &mut encoder_availability.hevc_nvenc),
-                    ("hevc_qsv", encoder_availability.hevc_qsv, &mut encoder_availability.hevc_qsv),
-                    ("libx265", true, &mut dummy_flag),
-                ],
-                "vulkan" => vec![
-                    ("hevc_vulkan", encoder_availability.hevc_vulkan, &mut encoder_availability.hevc_vulkan),
-                    ("hevc_nvenc", encoder_availability.hevc_nvenc, &mut encoder_availability.hevc_nvenc),
-                    ("hevc_qsv", encoder_availability.hevc_qsv, &mut encoder_availability.hevc_qsv),
-                    ("hevc_amf", encoder_availability.hevc_amf, &mut encoder_availability.hevc_amf),
-                    ("libx265", true, &mut dummy_flag),
-                ],
-                "cpu" => vec![
-                    ("libx265", true, &mut dummy_flag),
-                ],
-                _ => vec![ // auto
-                    ("hevc_nvenc", encoder_availability.hevc_nvenc, &mut encoder_availability.hevc_nvenc),
-                    ("hevc_qsv", encoder_availability.hevc_qsv, &mut encoder_availability.hevc_qsv),
-                    ("hevc_amf", encoder_availability.hevc_amf, &mut encoder_availability.hevc_amf),
-                    ("hevc_vulkan", encoder_availability.hevc_vulkan, &mut encoder_availability.hevc_vulkan),
-                    ("libx265", true, &mut dummy_flag),
-                ],
-            }
-        },
-        "av1" => {
-            match encoder_type {
-                "nvenc" => vec![
-                    ("av1_nvenc", encoder_availability.av1_nvenc, &mut encoder_availability.av1_nvenc),
-                    ("av1_qsv", encoder_availability.av1_qsv, &mut encoder_availability.av1_qsv),
-                    ("av1_amf", encoder_availability.av1_amf, &mut encoder_availability.av1_amf),
-                    ("libaom-av1", true, &mut dummy_flag),
-                ],
-                "qsv" => vec![
-                    ("av1_qsv", encoder_availability.av1_qsv, &mut encoder_availability.av1_qsv),
-                    ("av1_nvenc", encoder_availability.av1_nvenc, &mut encoder_availability.av1_nvenc),
-                    ("av1_amf", encoder_availability.av1_amf, &mut encoder_availability.av1_amf),
-                    ("libaom-av1", true, &mut dummy_flag),
-                ],
-                "amf" => vec![
-                    ("av1_amf", encoder_availability.av1_amf, &mut encoder_availability.av1_amf),
-                    ("av1_nvenc", encoder_availability.av1_nvenc, &mut encoder_availability.av1_nvenc),
-                    ("av1_qsv", encoder_availability.av1_qsv, &mut encoder_availability.av1_qsv),
-                    ("libaom-av1", true, &mut dummy_flag),
-                ],
-                "vulkan" => vec![
-                    ("av1_nvenc", encoder_availability.av1_nvenc, &mut encoder_availability.av1_nvenc),
-                    ("av1_qsv", encoder_availability.av1_qsv, &mut encoder_availability.av1_qsv),
-                    ("av1_amf", encoder_availability.av1_amf, &mut encoder_availability.av1_amf),
-                    ("libaom-av1", true, &mut dummy_flag),
-                ],
-                "cpu" => vec![
-                    ("libaom-av1", true, &mut dummy_flag),
-                ],
-                _ => vec![ // auto
-                    ("av1_nvenc", encoder_availability.av1_nvenc, &mut encoder_availability.av1_nvenc),
-                    ("av1_qsv", encoder_availability.av1_qsv, &mut encoder_availability.av1_qsv),
-                    ("av1_amf", encoder_availability.av1_amf, &mut encoder_availability.av1_amf),
-                    ("libaom-av1", true, &mut dummy_flag),
-                ],
-            }
-        },
-        _ => { // h264
-            match encoder_type {
-                "nvenc" => vec![
-                    ("h264_nvenc", encoder_availability.h264_nvenc, &mut encoder_availability.h264_nvenc),
-                    ("h264_vulkan", encoder_availability.h264_vulkan, &mut encoder_availability.h264_vulkan),
-                    ("h264_qsv", encoder_availability.h264_qsv, &mut encoder_availability.h264_qsv),
-                    ("h264_amf", encoder_availability.h264_amf, &mut encoder_availability.h264_amf),
-                    ("libx264", true, &mut dummy_flag),
-                ],
-                "qsv" => vec![
-                    ("h264_qsv", encoder_availability.h264_qsv, &mut encoder_availability.h264_qsv),
-                    ("h264_vulkan", encoder_availability.h264_vulkan, &mut encoder_availability.h264_vulkan),
-                    ("h264_nvenc", encoder_availability.h264_nvenc, &mut encoder_availability.h264_nvenc),
-                    ("h264_amf", encoder_availability.h264_amf, &mut encoder_availability.h264_amf),
-                    ("libx264", true, &mut dummy_flag),
-                ],
-                "amf" => vec![
-                    ("h264_amf", encoder_availability.h264_amf, &mut encoder_availability.h264_amf),
-                    ("h264_vulkan", encoder_availability.h264_vulkan, &mut encoder_availability.h264_vulkan),
-                    ("h264_nvenc", encoder_availability.h264_nvenc, &mut encoder_availability.h264_nvenc),
-                    ("h264_qsv", encoder_availability.h264_qsv, &mut encoder_availability.h264_qsv),
-                    ("libx264", true, &mut dummy_flag),
-                ],
-                "vulkan" => vec![
-                    ("h264_vulkan", encoder_availability.h264_vulkan, &mut encoder_availability.h264_vulkan),
-                    ("h264_nvenc", encoder_availability.h264_nvenc, &mut encoder_availability.h264_nvenc),
-                    ("h264_qsv", encoder_availability.h264_qsv, &mut encoder_availability.h264_qsv),
-                    ("h264_amf", encoder_availability.h264_amf, &mut encoder_availability.h264_amf),
-                    ("libx264", true, &mut dummy_flag),
-                ],
-                "cpu" => vec![
-                    ("libx264", true, &mut dummy_flag),
-                ],
-                _ => vec![ // auto
-                    ("h264_nvenc", encoder_availability.h264_nvenc, &mut encoder_availability.h264_nvenc),
-                    ("h264_qsv", encoder_availability.h264_qsv, &mut encoder_availability.h264_qsv),
-                    ("h264_amf", encoder_availability.h264_amf, &mut encoder_availability.h264_amf),
-                    ("h264_vulkan", encoder_availability.h264_vulkan, &mut encoder_availability.h264_vulkan),
-                    ("libx264", true, &mut dummy_flag),
-                ],
-            }
-        },
-    };
-
-
-    let ffmpeg_encoder = candidates
-        .iter()
-        .find(|&&(_name, available, _)| available)
-        .map(|&(name, _, _)| name)
-        .expect("At least one software encoder is available.");
-
-    info!(
-    "=== Encoder Selection ===\n\
-     Video codec: {}\n\
-     User preference: {}\n\
-     --- Encoder Availability ---\n\
-       h264_nvenc: {}\n\
-       h264_qsv: {}\n\
-       h264_amf: {}\n\
-       h264_vulkan: {}\n\
-       hevc_nvenc: {}\n\
-       hevc_qsv: {}\n\
-       hevc_amf: {}\n\
-       hevc_vulkan: {}\n\
-       av1_nvenc: {}\n\
-       av1_qsv: {}\n\
-       av1_amf: {}\n\
-       av1_vulkan: {}",
-    params.config.video_codec,
-    params.config.encoder,
-    encoder_availability.h264_nvenc,
-    encoder_availability.h264_qsv,
-    encoder_availability.h264_amf,
-    encoder_availability.h264_vulkan,
-    encoder_availability.hevc_nvenc,
-    encoder_availability.hevc_qsv,
-    encoder_availability.hevc_amf,
-    encoder_availability.hevc_vulkan,
-    encoder_availability.av1_nvenc,
-    encoder_availability.av1_qsv,
-    encoder_availability.av1_amf,
-    encoder_availability.av1_vulkan,
-    );
-    if !hw_errors.is_empty() {
-        info!("  --- Encoder Errors ---");
-        for error in &hw_errors {
-            info!("    {}", error);
-        }
-    }
-    info!("  Selected encoder: {}", ffmpeg_encoder);
-    info!("=========================");
-
-    let ffmpeg_preset = match ffmpeg_encoder {
-        "h264_amf" | "hevc_amf" | "av1_amf" => "-quality",
-        "h264_vulkan" | "hevc_vulkan" | "av1_vulkan" => "-preset",
-        _ => "-preset",
-    };
-
-    let ffmpeg_preset_name = match ffmpeg_encoder {
-        "h264_nvenc" | "hevc_nvenc" | "av1_nvenc" => params
-            .config
-            .ffmpeg_preset
-            .split_whitespace()
-            .nth(1)
-            .unwrap_or("p4"),
-        "h264_qsv" | "hevc_qsv" | "av1_qsv" => params
-            .config
-            .ffmpeg_preset
-            .split_whitespace()
-            .next()
-            .unwrap_or("medium"),
-        "h264_amf" | "hevc_amf" | "av1_amf" => params
-            .config
-            .ffmpeg_preset
-            .split_whitespace()
-            .nth(2)
-            .unwrap_or("balanced"),
-        "h264_vulkan" | "hevc_vulkan" | "av1_vulkan" => params
-            .config
-            .ffmpeg_preset
-            .split_whitespace()
-            .next()
-            .unwrap_or("default"),
-        _ => params
-            .config
-            .ffmpeg_preset
-            .split_whitespace()
-            .next()
-            .unwrap_or("medium"),
-    };
-
-    let bitrate_control = if params.config.bitrate_control == "CRF" {
-        match ffmpeg_encoder {
-            "h264_nvenc" | "hevc_nvenc" | "av1_nvenc" => "-cq",
-            "h264_qsv" | "hevc_qsv" | "av1_qsv" => "-q",
-            "h264_amf" | "hevc_amf" | "av1_amf" => "-qp_p",
-            "h264_vulkan" | "hevc_vulkan" | "av1_vulkan" => "-qp",
-            _ => "-crf",
-        }
-    } else {
-        "-b:v"
-    };
-
-    if params.config.hardware_accel {
-        let h264_supported = encoder_availability.h264_nvenc
-            || encoder_availability.h264_qsv
-            || encoder_availability.h264_amf
-            || encoder_availability.h264_vulkan;
-        let hevc_supported = encoder_availability.hevc_nvenc
-            || encoder_availability.hevc_qsv
-            || encoder_availability.hevc_amf
-            || encoder_availability.hevc_vulkan;
-        let av1_supported = encoder_availability.av1_nvenc
-            || encoder_availability.av1_qsv
-            || encoder_availability.av1_amf
-            || encoder_availability.av1_vulkan;
-
-        if (params.config.video_codec == "h264" && !h264_supported)
-            || (params.config.video_codec == "hevc" && !hevc_supported)
-            || (params.config.video_codec == "av1" && !av1_supported) {
-            let mut detailed_error = String::new();
-            detailed_error += &format!("{}\n", tl!("no-hwacc"));
-
-            detailed_error += &format!(
-                "Hardware detection summary:\n\
-         - NVIDIA: {}\n\
-         - Intel Quick Sync: {}\n\
-         - AMD AMF: {}\n\
-         - Vulkan: {}\n\n",
-                hw_detected.h264_nvenc,
-                hw_detected.h264_qsv,
-                hw_detected.h264_amf,
-                hw_detected.h264_vulkan
-            );
-
-            detailed_error += "Encoder test results:\n";
-            detailed_error += &format!(
-                "- h264_nvenc: {}\n",
-                if encoder_availability.h264_nvenc { "SUCCESS" } else { "FAILED" }
-            );
-            detailed_error += &format!(
-                "- hevc_nvenc: {}\n",
-                if encoder_availability.hevc_nvenc { "SUCCESS" } else { "FAILED" }
-            );
-            detailed_error += &format!(
-                "- av1_nvenc: {}\n",
-                if encoder_availability.av1_nvenc { "SUCCESS" } else { "FAILED" }
-            );
-            detailed_error += &format!(
-                "- h264_qsv: {}\n",
-                if encoder_availability.h264_qsv { "SUCCESS" } else { "FAILED" }
-            );
-            detailed_error += &format!(
-                "- hevc_qsv: {}\n",
-                if encoder_availability.hevc_qsv { "SUCCESS" } else { "FAILED" }
-            );
-            detailed_error += &format!(
-                "- av1_qsv: {}\n",
-                if encoder_availability.av1_qsv { "SUCCESS" } else { "FAILED" }
-            );
-            detailed_error += &format!(
-                "- h264_amf: {}\n",
-                if encoder_availability.h264_amf { "SUCCESS" } else { "FAILED" }
-            );
-            detailed_error += &format!(
-                "- hevc_amf: {}\n",
-                if encoder_availability.hevc_amf { "SUCCESS" } else { "FAILED" }
-            );
-            detailed_error += &format!(
-                "- av1_amf: {}\n",
-                if encoder_availability.av1_amf { "SUCCESS" } else { "FAILED" }
-            );
-            detailed_error += &format!(
-                "- h264_vulkan: {}\n",
-                if encoder_availability.h264_vulkan { "SUCCESS" } else { "FAILED" }
-            );
-            detailed_error += &format!(
-                "- hevc_vulkan: {}\n",
-                if encoder_availability.hevc_vulkan { "SUCCESS" } else { "FAILED" }
-            );
-            detailed_error += &format!(
-                "- av1_vulkan: {}\n",
-                if encoder_availability.av1_vulkan { "SUCCESS" } else { "FAILED" }
-            );
-            detailed_error += &format!(
-                "- h264_cuvid: {}\n",
-                if encoder_availability.h264_cuvid { "SUCCESS" } else { "FAILED" }
-            );
-            detailed_error += &format!(
-                "- hevc_cuvid: {}\n",
-                if encoder_availability.hevc_cuvid { "SUCCESS" } else { "FAILED" }
-            );
-            detailed_error += &format!(
-                "- av1_cuvid: {}\n\n",
-                if encoder_availability.av1_cuvid { "SUCCESS" } else { "FAILED" }
-            );
-
-            // 详细的错误日志
-            if !hw_errors.is_empty() {
-                detailed_error += "Detailed error logs:\n";
-                for (i, error) in hw_errors.iter().enumerate() {
-                    detailed_error += &format!("{}. {}\n", i + 1, error);
-                }
-                detailed_error += "\n";
-            } else {
-                detailed_error += "No hardware encoders were tested (all detection failed).\n\n";
-            }
-
-            bail!(detailed_error);
-        }
+    let input_pix_fmt = if deep { "rgba64le" } else { "rgba" };
+    let mut video_input = Input::raw_video(vw, vh, fps, input_pix_fmt);
+    if params.config.ffmpeg_thread {
+        video_input = video_input.option("-thread_queue_size", "2048");
     }
-    let global_args = "-y";
-    let mut input_args = String::new();
-    write!(&mut input_args, "-f rawvideo -c:v rawvideo -s {vw}x{vh} -r {fps} -pix_fmt rgba -i - -i")?;
-
-    let ffmpeg_thread = if params.config.ffmpeg_thread {
-        "-thread_queue_size 2048 "
-    } else {
-        ""
-    };
+    video_input = video_input.args(user_args(&params.config.extra_input_args).context(ErrorKind::InvalidConfig)?);
+    let extra_output_args = user_args(&params.config.extra_output_args).context(ErrorKind::InvalidConfig)?;
 
     let video = match params.config.video {
         true => "mov",
         false => "mp4",
     };
 
-    let strict_flag = if params.config.audio_format == "flac" && video == "mp4" {
-        "-strict -2 "
-    } else {
-        ""
+    // Frames come in upside down. Every output flips them once, first thing, so that the extra
+    // filters and the encoder filters see them the right way up
+    let extra_filters = params.config.extra_video_filters.trim();
+    let flip = match extra_filters.is_empty() {
+        true => "vflip".to_owned(),
+        false => format!("vflip,{extra_filters}"),
     };
-    let is_vulkan_encoder = ffmpeg_encoder.ends_with("_vulkan");
-    let video_filter = if is_vulkan_encoder {
-        // Vulkan encoders need NV12 format uploaded to Vulkan device memory
-        // vflip is needed because OpenGL renders top-to-bottom but video expects bottom-to-top
-        "format=nv12,vflip,hwupload"
-    } else {
-        "format=yuv420p,vflip"
-    };
-
-    let args2 = if is_vulkan_encoder {
-        // Vulkan
-        format!(
-            "-c:a {} -c:v {} {} {} -map 0:v:0 -map 1:a:0 {} {} {} -vf {} -f {}",
-            audio_codec,
-            ffmpeg_encoder,
-            bitrate_control,
-            params.config.bitrate,
-            strict_flag,
-            ffmpeg_thread,
-            if params.config.disable_loading {
-                format!("-ss {}", LoadingScene::TOTAL_TIME + GameScene::BEFORE_TIME)
-            } else {
-                "-ss 0.1".to_string()
-            },
-            video_filter,
-            video,
-        )
+    let first_frame = (trim_start(&params.config) * fps as f64).round() as u64;
+
+    let mut output = if image_sequence {
+        // Frames are numbered from the first one that ends up in a video, matching the trimmed audio
+        let pattern = output_path.join(format!("frame_%06d.{}", params.config.image_format.to_lowercase()));
+        let command = FfmpegCommand::new().input(video_input).output(
+            Output::new()
+                .filter("v", &flip)
+                .args(image_codec_args(&params.config).context(ErrorKind::InvalidConfig)?)
+                .option("-start_number", "0")
+                .args(&extra_output_args)
+                .format("image2"),
+            pattern,
+        );
+        let (proc, input) = spawn_encoder(&ffmpeg, &command)?;
+        VideoOutput::Single {
+            proc,
+            input,
+            frames: first_frame..frames,
+        }
+    } else if transparent {
+        let (video_codec, pix_fmt, alpha_audio_codec) = alpha_codec_args(&params.config).context(ErrorKind::InvalidConfig)?;
+        let mut alpha = Output::new()
+            .map("0:v:0")
+            .map("1:a:0")
+            .args(video_codec)
+            .option("-pix_fmt", pix_fmt)
+            .codec("a", alpha_audio_codec)
+            .seek(trim_start(&params.config))
+            .args(&extra_output_args)
+            .format(params.config.alpha_container());
+        // QuickTime Animation stays RGB, the others are converted and tagged like any video
+        alpha = if pix_fmt.starts_with("yuv") {
+            alpha.filter("v", format!("{flip},{},format={pix_fmt}", color.scale_filter())).args(color.tags())
+        } else {
+            alpha.filter("v", &flip)
+        };
+        let command = FfmpegCommand::new()
+            .input(video_input)
+            .input(Input::new(mixing_output.path()))
+            .output(alpha, &output_path);
+        let (proc, input) = spawn_encoder(&ffmpeg, &command)?;
+        VideoOutput::Single { proc, input, frames: 0..frames }
     } else {
-        format!(
-            "-c:a {} -c:v {} {} {} {} {} -map 0:v:0 -map 1:a:0 {} {} {} -vf {} -f {}",
-            audio_codec,
-            ffmpeg_encoder,
-            bitrate_control,
-            params.config.bitrate,
-            ffmpeg_preset,
-            ffmpeg_preset_name,
-            strict_flag,
-            ffmpeg_thread,
-            if params.config.disable_loading {
-                format!("-ss {}", LoadingScene::TOTAL_TIME + GameScene::BEFORE_TIME)
-            } else {
-                "-ss 0.1".to_string()
-            },
-            video_filter,
-            video,
-        )
-    };
-
-    let mut proc = {
-        let mut cmd = cmd_hidden(&ffmpeg);
-        cmd.args(global_args.split_whitespace());
-        if is_vulkan_encoder
-        {
-            cmd.arg("-init_hw_device").arg("vulkan=vk")
-               .arg("-filter_hw_device").arg("vk");
+        // Frames and alpha videos have fixed codecs, only these outputs need an encoder picked
+        let probe = match params.encoders {
+            // Probed once by the GUI process and shared between tasks
+            Some(probe) if probe.key.ffmpeg == ffmpeg => probe,
+            _ => EncoderProbe::cached(&ffmpeg, default_config_dir().as_deref(), false)?,
+        };
+        let encoder = select_encoder(&params.config, probe)?;
+        let ffmpeg_encoder = encoder.name;
+
+        let preset_args: Vec<String> = encoder
+            .preset
+            .iter()
+            .flat_map(|preset| [preset.flag.to_owned(), preset.value(&params.config)])
+            .collect();
+
+        let duration = frames as f64 / fps as f64 - trim_start(&params.config);
+        let rate_args = rate_control_args(&params.config, encoder, duration).context(ErrorKind::InvalidConfig)?;
+
+        let two_pass = params.config.two_pass() && encoder.two_pass.is_some();
+        if params.config.two_pass() && !two_pass {
+            let message = format!("{ffmpeg_encoder} does not support two-pass encoding, encoding in one pass");
+            warn!("{message}");
+            if part == RenderPart::Analysis {
+                send(IPCEvent::Warning(message));
+                send(IPCEvent::Done(render_start_time.elapsed().as_secs_f64()));
+                return Ok(());
+            }
+        }
+        let (pass_dir, pass_log) = pass_log(&output_path);
+
+        let video_filter = format!("{flip},{},{}", color.scale_filter(), encoder.video_filter(params.config.bit_depth));
+        let encode = Output::new()
+            .codec("v", ffmpeg_encoder)
+            .args(&rate_args)
+            .args(&preset_args)
+            .args(color.tags())
+            .filter("v", &video_filter);
+        let encoder_command = FfmpegCommand::new()
+            .global(encoder.hw_device_args())
+            .input(video_input);
+
+        // Segments and slices are video-only, the audio is muxed in when they are joined
+        let video_only = encode.clone().no_audio().args(&extra_output_args).format("matroska");
+
+        if part == RenderPart::Analysis {
+            // Sees exactly the frames of the second pass, trimmed by the same `-ss`
+            let command = encoder_command
+                .output(
+                    encode
+                        .no_audio()
+                        .seek(trim_start(&params.config))
+                        .args(with_pass_args(&extra_output_args, encoder.pass_args(1, &pass_log)))
+                        .format("null"),
+                    "-",
+                )
+                .current_dir(&pass_dir);
+            let (proc, input) = spawn_encoder(&ffmpeg, &command)?;
+            VideoOutput::Single { proc, input, frames: 0..frames }
+        } else if let RenderPart::Slice { index, count } = part {
+            let frames = slice_range(first_frame, frames, index, count);
+            info!("Rendering slice {}/{}: frames {:?}", index + 1, count, frames);
+            let (proc, input) = spawn_encoder(&ffmpeg, &encoder_command.output(video_only, &output_path))?;
+            VideoOutput::Single { proc, input, frames }
+        } else if params.config.segment_length > 0. && !two_pass {
+            let segment_frames = ((params.config.segment_length * fps as f64).round() as u64).max(1);
+            VideoOutput::Segmented(SegmentedOutput::open(
+                segment_dir(&output_path),
+                segment_fingerprint(&params.config, &path, frames, ffmpeg_encoder),
+                ffmpeg.clone(),
+                (encoder_command, video_only),
+                first_frame,
+                segment_frames,
+            )?)
+        } else {
+            let mut args = encode.codec("a", &audio_codec).map("0:v:0").map("1:a:0");
+            if params.config.audio_format == "flac" && video == "mp4" {
+                args = args.option("-strict", "-2");
+            }
+            args = args.seek(trim_start(&params.config));
+            let mut command = encoder_command.input(Input::new(mixing_output.path()));
+            let mut pass_args = Vec::new();
+            if two_pass {
+                pass_args = encoder.pass_args(2, &pass_log);
+                command = command.current_dir(&pass_dir);
+            }
+            args = args.args(with_pass_args(&extra_output_args, pass_args));
+            let (proc, input) = spawn_encoder(&ffmpeg, &command.output(args.format(video), &output_path))?;
+            VideoOutput::Single { proc, input, frames: 0..frames }
         }
-        cmd.args(input_args.split_whitespace())
-            .arg(mixing_output.path())
-            .args(args2.split_whitespace())
-            .arg(output_path)
-            .arg("-loglevel")
-            .arg("error")
-            .stdin(Stdio::piped())
-            .stderr(Stdio::inherit())
-            .spawn()
-            .with_context(|| tl!("run-ffmpeg-failed"))?
     };
-    let mut input = proc.stdin.take().unwrap();
 
-    let rgba_size = vw as usize * vh as usize * 4;
+    // 16 bits per channel for 10-bit output, read back from the half float buffers
+    let rgba_size = vw as usize * vh as usize * if deep { 8 } else { 4 };
     info!("RGBA buffer size: {}", rgba_size);
+    let read_fbo = internal_id(&mst.output());
+    let read_type = if deep { GL_UNSIGNED_SHORT } else { GL_UNSIGNED_BYTE };
 
     const MAX_PBO_COUNT: usize = 8;
     let n = MAX_PBO_COUNT.min(fps as usize).max(2);
@@ -1480,20 +1910,44 @@ pub async fn main() -> Result<()> {
         glBindBuffer(GL_PIXEL_PACK_BUFFER, 0);
     }
 
-    send(IPCEvent::StartRender(frames));
-
     let fps_f64 = params.config.fps as f64;
     let frame_duration = 1.0 / fps_f64;
-    let total_frames = frames;
+    // A slice stops once its range is encoded
+    let total_frames = frames.min(output.end());
+
+    // Frames before the first one to encode (frames before a slice, resumed segments) are skipped
+    // by jumping the clock ahead. The scenes start their own clocks when they are entered, so the
+    // loading screen and the game scene's end are always played through, and the chart is
+    // warmed up for a while before the first encoded frame so that the hit effects of the
+    // skipped notes have faded. Warm-up frames are rendered, but not read back or sent to the
+    // task view.
+    const WARMUP: f64 = 2.;
+    let intro_end = ((O + frame_duration) * fps_f64).ceil() as u64;
+    // The music ends a second before `length`
+    let outro_start = ((O + length - 2.) * fps_f64).floor() as u64;
+    let seek_to = output.start().saturating_sub((WARMUP * fps_f64) as u64).min(outro_start).max(intro_end);
+    let schedule = (0..total_frames).filter(|&frame| frame < intro_end || frame >= seek_to);
+    if seek_to > intro_end {
+        info!("Seeking from frame {} to {}", intro_end, seek_to);
+    }
+    let frames_to_encode = (0..total_frames).filter(|&frame| output.wants(frame)).count() as u64;
+    if matches!(output, VideoOutput::Segmented(_)) && frames_to_encode != total_frames {
+        info!("Resuming: {} of {} frames left to encode", frames_to_encode, total_frames);
+    }
+    send(IPCEvent::StartRender(frames_to_encode));
 
-    let frames10 = total_frames / 10;
+    let frames10 = (total_frames / 10).max(1);
     let mut step_time = Instant::now();
     let mut current_pbo_index = 0;
+    // PBO that still holds a frame waiting to be mapped, read back one frame late so that the
+    // transfer overlaps with rendering the next frame
+    let mut unread: Option<(usize, u64)> = None;
     let mut fps_update_timer = Instant::now();
     let mut fps_frame_count = 0u64;
     let mut realtime_fps = 0u64;
 
-    for frame in 0..total_frames {
+    for frame in schedule {
+        let frame_start = Instant::now();
         if frame % frames10 == 0 || frame == total_frames - 1 {
             let progress = (frame as f64 / total_frames as f64).min(1.0);
             let percent = (progress * 100.).ceil() as i8;
@@ -1521,9 +1975,13 @@ pub async fn main() -> Result<()> {
 
         let current_frame_time = frame as f64 * frame_duration;
         *my_time.borrow_mut() = current_frame_time;
-        let output = mst.output();
-        let render_pass: MQRenderPass = unsafe { std::mem::transmute(output.render_pass) };
+        let target = mst.output();
+        let render_pass: MQRenderPass = unsafe { std::mem::transmute(target.render_pass) };
         gl.quad_gl.render_pass(Some(render_pass));
+        if transparent {
+            clear_background(Color::new(0., 0., 0., 0.));
+            unsafe { blend_alpha_separately() };
+        }
         main.update()?;
         main.render(&mut painter)?;
         if current_frame_time <= LoadingScene::TOTAL_TIME as f64 && !params.config.disable_loading {
@@ -1534,56 +1992,41 @@ pub async fn main() -> Result<()> {
             mst.blit();
         }
 
+        if !output.wants(frame) {
+            continue;
+        }
+
         unsafe {
             use miniquad::gl::*;
-            if frame > 0 {
-                glBindBuffer(GL_PIXEL_PACK_BUFFER, pbos[current_pbo_index]);
-                let src = glMapBufferRange(
-                    GL_PIXEL_PACK_BUFFER,
-                    0,
-                    rgba_size as _,
-                    GL_MAP_READ_BIT
-                );
-                if !src.is_null() {
-                    let data_slice = std::slice::from_raw_parts(src as *const u8, rgba_size);
-                    input.write_all(data_slice)?;
-                    glUnmapBuffer(GL_PIXEL_PACK_BUFFER);
-                } else {
-                    bail!("Failed to map PBO at frame {}", frame);
-                }
-                glBindBuffer(GL_PIXEL_PACK_BUFFER, 0);
-            }
             let next_pbo_index = (current_pbo_index + 1) % n;
 
-            glBindFramebuffer(GL_READ_FRAMEBUFFER, internal_id(&mst.output()));
+            glBindFramebuffer(GL_READ_FRAMEBUFFER, read_fbo);
             glBindBuffer(GL_PIXEL_PACK_BUFFER, pbos[next_pbo_index]);
             glReadPixels(
                 0, 0,
                 vw as _, vh as _,
-                GL_RGBA, GL_UNSIGNED_BYTE,
+                GL_RGBA, read_type,
                 std::ptr::null_mut()
             );
-
             glBindBuffer(GL_PIXEL_PACK_BUFFER, 0);
             glBindFramebuffer(GL_READ_FRAMEBUFFER, 0);
-            if frame == total_frames - 1 {
-                glBindBuffer(GL_PIXEL_PACK_BUFFER, pbos[next_pbo_index]);
-                glFinish();
+
+            if let Some((index, unread_frame)) = unread.replace((next_pbo_index, frame)) {
+                glBindBuffer(GL_PIXEL_PACK_BUFFER, pbos[index]);
                 let src = glMapBufferRange(
                     GL_PIXEL_PACK_BUFFER,
                     0,
                     rgba_size as _,
                     GL_MAP_READ_BIT
                 );
-                if !src.is_null() {
-                    let data_slice = std::slice::from_raw_parts(src as *const u8, rgba_size);
-                    input.write_all(data_slice)?;
-                    glUnmapBuffer(GL_PIXEL_PACK_BUFFER);
-                } else {
-                    bail!("Failed to map final PBO at frame {}", frame);
+                if src.is_null() {
+                    bail!("Failed to map PBO at frame {}", unread_frame);
                 }
-
+                let data_slice = std::slice::from_raw_parts(src as *const u8, rgba_size);
+                let result = output.write_frame(unread_frame, data_slice);
+                glUnmapBuffer(GL_PIXEL_PACK_BUFFER);
                 glBindBuffer(GL_PIXEL_PACK_BUFFER, 0);
+                result?;
             }
             current_pbo_index = next_pbo_index;
         }
@@ -1596,11 +2039,50 @@ pub async fn main() -> Result<()> {
             fps_update_timer = Instant::now();
         }
 
-        send(IPCEvent::Frame);
+        send(IPCEvent::Frame {
+            elapsed: frame_start.elapsed().as_secs_f32(),
+        });
     }
 
-    drop(input);
-    proc.wait()?;
+    if let Some((index, unread_frame)) = unread.take() {
+        unsafe {
+            use miniquad::gl::*;
+            glBindBuffer(GL_PIXEL_PACK_BUFFER, pbos[index]);
+            glFinish();
+            let src = glMapBufferRange(
+                GL_PIXEL_PACK_BUFFER,
+                0,
+                rgba_size as _,
+                GL_MAP_READ_BIT
+            );
+            if src.is_null() {
+                bail!("Failed to map final PBO at frame {}", unread_frame);
+            }
+            let data_slice = std::slice::from_raw_parts(src as *const u8, rgba_size);
+            let result = output.write_frame(unread_frame, data_slice);
+            glUnmapBuffer(GL_PIXEL_PACK_BUFFER);
+            glBindBuffer(GL_PIXEL_PACK_BUFFER, 0);
+            result?;
+        }
+    }
+
+    send(IPCEvent::StartFinalizing);
+    match output {
+        VideoOutput::Single { mut proc, input, .. } => {
+            drop(input);
+            let status = proc.wait()?;
+            if !status.success() {
+                bail!("ffmpeg exited abnormally ({status})");
+            }
+        }
+        VideoOutput::Segmented(segments) => {
+            let dir = segments.dir.clone();
+            let list = segments.finish()?;
+            mux_video_parts(&ffmpeg, &list, mixing_output.path(), &params.config, &output_path)
+                .with_context(|| format!("completed segments are kept in {}", dir.display()))?;
+            std::fs::remove_dir_all(&dir)?;
+        }
+    }
 
     info!("Render Time: {:.2?}", render_start_time.elapsed());
     info!("Average FPS: {}", realtime_fps);
@@ -1612,4 +2094,4 @@ pub async fn main() -> Result<()> {
 
     send(IPCEvent::Done(render_start_time.elapsed().as_secs_f64()));
     Ok(())
-}
\ No newline at end of file
+}
@@ -1,33 +1,39 @@
 use crate::{
-    common::output_dir,
-    render::{IPCEvent, RenderParams},
+    common::{output_dir, CONFIG_DIR, DATA_DIR},
+    encoder::{self, shared_probe},
+    ipc::{self, PROTOCOL_VERSION},
+    mixer::LoudnessReport,
+    render::{find_ffmpeg, mux_video_parts, remove_pass_logs, write_concat_list, ErrorKind, IPCEvent, RenderConfig, RenderParams, RenderPart},
     ASSET_PATH,
 };
-use anyhow::Result;
+use anyhow::{anyhow, bail, Context, Result};
 use chrono::Local;
+use futures::future::join_all;
 use prpr::fs;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use std::{
-    collections::VecDeque,
+    collections::{HashMap, VecDeque},
+    fmt::Write as _,
     io::Write,
     ops::DerefMut,
-    path::PathBuf,
+    path::{Path, PathBuf},
     process::Stdio,
     sync::{
         atomic::{AtomicBool, Ordering},
-        Arc,
+        Arc, OnceLock,
     },
-    time::Instant,
+    time::{Duration, Instant},
 };
 use tempfile::NamedTempFile;
 use tokio::{
-    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
-    sync::{mpsc, Mutex},
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader, Lines},
+    process::ChildStdout,
+    sync::{Mutex, Notify},
     task::JoinHandle,
 };
-use tracing::{error, info};
+use tracing::{error, info, warn};
 
-#[derive(Serialize, Clone)]
+#[derive(Serialize, Deserialize, Clone)]
 #[serde(rename_all = "snake_case", tag = "type")]
 pub enum TaskStatus {
     Pending,
@@ -38,6 +44,7 @@ pub enum TaskStatus {
         fps: u64,
         estimate: f64,
     },
+    Finalizing,
     Done {
         duration: f64,
         output: String,
@@ -45,9 +52,163 @@ pub enum TaskStatus {
     Canceled,
     Failed {
         error: String,
+        #[serde(default)]
+        kind: ErrorKind,
+        /// Localized suggestion on how to fix it.
+        #[serde(default)]
+        hint: Option<String>,
     },
 }
 
+/// What gets written to `tasks.json` for each task, see [`TaskQueue::restore`].
+#[derive(Serialize)]
+struct TaskRecordRef<'a> {
+    name: &'a str,
+    output: &'a PathBuf,
+    params: &'a RenderParams,
+    status: TaskStatus,
+}
+
+#[derive(Deserialize)]
+struct TaskRecord {
+    name: String,
+    output: PathBuf,
+    params: RenderParams,
+    status: TaskStatus,
+}
+
+fn tasks_file() -> PathBuf {
+    DATA_DIR.get().unwrap().join("tasks.json")
+}
+
+/// Finished, failed and canceled tasks kept in `tasks.json`, older ones are dropped on save.
+const MAX_FINISHED_RECORDS: usize = 50;
+
+async fn save_tasks(tasks: &[Arc<Task>]) -> Result<()> {
+    let mut statuses = Vec::with_capacity(tasks.len());
+    for task in tasks {
+        statuses.push(task.status.lock().await.clone());
+    }
+    let is_finished = |status: &TaskStatus| matches!(status, TaskStatus::Done { .. } | TaskStatus::Failed { .. } | TaskStatus::Canceled);
+    let mut skip_finished = statuses.iter().filter(|it| is_finished(it)).count().saturating_sub(MAX_FINISHED_RECORDS);
+    let records: Vec<_> = tasks
+        .iter()
+        .zip(statuses)
+        .filter(|(_, status)| {
+            let skip = skip_finished > 0 && is_finished(status);
+            skip_finished -= skip as usize;
+            !skip
+        })
+        .map(|(task, status)| TaskRecordRef {
+            name: &task.name,
+            output: &task.output,
+            params: &task.params,
+            status,
+        })
+        .collect();
+    let json = serde_json::to_vec(&records)?;
+
+    // Write to a temporary file first so that a crash while saving can't corrupt the queue
+    let file = tasks_file();
+    let tmp = file.with_extension("json.tmp");
+    tokio::fs::write(&tmp, json).await?;
+    tokio::fs::rename(&tmp, &file).await?;
+    Ok(())
+}
+
+/// Weight of the newest frame in the moving average of frame times.
+const FRAME_TIME_SMOOTHING: f64 = 0.05;
+
+/// Frame counts summed over all render processes of a task.
+struct Progress {
+    total: u64,
+    frames: u64,
+    /// Moving average of the frame time reported by each process that is still rendering, keyed
+    /// by process id.
+    frame_times: HashMap<u32, f64>,
+}
+
+impl Progress {
+    fn new() -> Self {
+        Self {
+            total: 0,
+            frames: 0,
+            frame_times: HashMap::new(),
+        }
+    }
+
+    fn frame(&mut self, child: u32, elapsed: f32) {
+        self.frames += 1;
+        let elapsed = elapsed as f64;
+        self.frame_times
+            .entry(child)
+            .and_modify(|average| *average += (elapsed - *average) * FRAME_TIME_SMOOTHING)
+            .or_insert(elapsed);
+    }
+
+    /// `child` stopped rendering frames.
+    fn finish(&mut self, child: u32) {
+        self.frame_times.remove(&child);
+    }
+
+    /// Frames per second of all processes together, slices render side by side.
+    fn fps(&self) -> f64 {
+        self.frame_times.values().filter(|it| **it > 0.).map(|it| 1. / it).sum()
+    }
+
+    fn status(&self) -> TaskStatus {
+        let fps = self.fps();
+        TaskStatus::Rendering {
+            progress: (self.frames as f64 / self.total.max(1) as f64).min(1.),
+            fps: fps.round() as u64,
+            estimate: self.total.saturating_sub(self.frames).max(1) as f64 / fps.max(1.),
+        }
+    }
+}
+
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Waits for the child's `Hello`, keeping whatever it logged before.
+async fn handshake(lines: &mut Lines<BufReader<ChildStdout>>, log: &mut String) -> Result<()> {
+    while let Some(line) = lines.next_line().await? {
+        match ipc::server::decode::<IPCEvent>(&line) {
+            None => {
+                log.push_str(&line);
+                log.push('\n');
+            }
+            Some(Ok(IPCEvent::Hello { version })) if version == PROTOCOL_VERSION => return Ok(()),
+            Some(Ok(IPCEvent::Hello { version })) => {
+                bail!("render process speaks IPC protocol v{version}, expected v{PROTOCOL_VERSION}")
+            }
+            Some(Ok(_)) => bail!("render process did not start with a handshake"),
+            Some(Err(err)) => return Err(err.context("invalid handshake")),
+        }
+    }
+    bail!("render process exited before the handshake")
+}
+
+enum ChildOutcome {
+    Done { duration: Option<f64>, output: String },
+    Canceled,
+    Failed(Failure),
+}
+
+struct Failure {
+    error: String,
+    kind: ErrorKind,
+    hint: Option<String>,
+}
+
+impl Failure {
+    fn other(error: String) -> Self {
+        Self {
+            error,
+            kind: ErrorKind::Other,
+            hint: None,
+        }
+    }
+}
+
 pub struct Task {
     id: u32,
     name: String,
@@ -57,7 +218,11 @@ pub struct Task {
     params: RenderParams,
     status: Mutex<TaskStatus>,
     request_cancel: AtomicBool,
-    last_fps_update: Mutex<Instant>,
+    /// Vendor of the encoder the render is expected to pick, resolved when the task is queued.
+    encoder_key: OnceLock<String>,
+    encoder: Mutex<Option<String>>,
+    loudness: Mutex<Option<LoudnessReport>>,
+    warnings: Mutex<Vec<String>>,
 }
 
 impl Task {
@@ -65,10 +230,6 @@ impl Task {
         let mut fs = fs::fs_from_file(&params.path)?;
         let info = fs::load_info(fs.deref_mut()).await?;
         let mut cover = NamedTempFile::new()?;
-        let video = match params.config.video {
-            true => "mov",
-            false => "mp4",
-        };
         cover.write_all(&fs.load_file(&info.illustration).await?)?;
 
         let level: String = info
@@ -83,9 +244,13 @@ impl Task {
             .filter(|&it| it == '-' || it == '_' || it == ' ' || it.is_alphanumeric())
             .collect();
         let file_name = format!(
-            "{} {safe_name}_{level}.{video}",
+            "{} {safe_name}_{level}",
             Local::now().format("%Y-%m-%d %H-%M-%S")
         );
+        let file_name = match params.config.output_extension() {
+            Some(extension) => format!("{file_name}.{extension}"),
+            None => file_name,
+        };
 
         let output = if let Some(path) = output_path {
             path.join(file_name)
@@ -93,134 +258,299 @@ impl Task {
             output_dir()?.join(file_name)
         };
 
-        Ok(Self {
+        Ok(Self::with_parts(id, info.name, cover, output, params, TaskStatus::Pending))
+    }
+
+    async fn restore(id: u32, record: TaskRecord) -> Result<Self> {
+        // The cover only lives in a temporary file, so it has to be extracted again. The chart may
+        // have been moved since, in which case the task is still listed without a cover.
+        let cover = async {
+            let mut fs = fs::fs_from_file(&record.params.path)?;
+            let info = fs::load_info(fs.deref_mut()).await?;
+            let mut cover = NamedTempFile::new()?;
+            cover.write_all(&fs.load_file(&info.illustration).await?)?;
+            Ok::<_, anyhow::Error>(cover)
+        }
+        .await;
+        let cover = match cover {
+            Ok(cover) => cover,
+            Err(err) => {
+                warn!("Failed to load cover for restored task #{id}: {err:?}");
+                NamedTempFile::new()?
+            }
+        };
+
+        // Tasks that were running when the app was closed have to start over
+        let status = match record.status {
+            TaskStatus::Loading | TaskStatus::Mixing | TaskStatus::Rendering { .. } | TaskStatus::Finalizing => TaskStatus::Pending,
+            status => status,
+        };
+
+        Ok(Self::with_parts(id, record.name, cover, record.output, record.params, status))
+    }
+
+    fn with_parts(id: u32, name: String, cover: NamedTempFile, output: PathBuf, params: RenderParams, status: TaskStatus) -> Self {
+        Self {
             id,
-            name: info.name,
+            name,
             cover,
             output,
 
             params,
-            status: Mutex::new(TaskStatus::Pending),
+            status: Mutex::new(status),
             request_cancel: AtomicBool::default(),
-            last_fps_update: Mutex::new(Instant::now()),
-        })
+            encoder_key: OnceLock::new(),
+            encoder: Mutex::default(),
+            loudness: Mutex::default(),
+            warnings: Mutex::default(),
+        }
     }
 
-    pub async fn run(&self) -> Result<()> {
+    async fn run(&self, scheduler: &Mutex<Scheduler>, notify: &Notify) -> Result<()> {
         info!("Task #{} started ({})", self.id, self.params.path.display());
 
         *self.status.lock().await = TaskStatus::Loading;
 
+        let start = Instant::now();
+        let progress = Mutex::new(Progress::new());
+        let abort = AtomicBool::new(false);
+        let config = &self.params.config;
+        let outcome = if config.two_pass() {
+            self.run_two_pass(&progress, &abort).await?
+        } else if config.render_slices > 1 && config.can_slice() {
+            let slots = Slots::new(scheduler, notify, encoder_key(self));
+            self.run_slices(config.render_slices, &slots, &progress, &abort).await?
+        } else {
+            self.run_child(RenderPart::Full, &self.output, &progress, &abort).await?
+        };
+
+        *self.status.lock().await = match outcome {
+            ChildOutcome::Done { duration, output } => TaskStatus::Done {
+                duration: duration.unwrap_or_else(|| start.elapsed().as_secs_f64()),
+                output,
+            },
+            ChildOutcome::Canceled => TaskStatus::Canceled,
+            ChildOutcome::Failed(Failure { error, kind, hint }) => TaskStatus::Failed { error, kind, hint },
+        };
+        Ok(())
+    }
+
+    /// Renders `count` time ranges and the audio track in parallel, as far as `slots` allow, then
+    /// joins them into the output file.
+    async fn run_slices(&self, count: u32, slots: &Slots<'_>, progress: &Mutex<Progress>, abort: &AtomicBool) -> Result<ChildOutcome> {
+        let dir = self.output.with_extension("slices");
+        tokio::fs::create_dir_all(&dir).await?;
+        let slices: Vec<PathBuf> = (0..count).map(|index| dir.join(format!("slice_{index:03}.mkv"))).collect();
+        let audio = dir.join("audio.track");
+
+        let mut parts: Vec<_> = slices
+            .iter()
+            .enumerate()
+            .map(|(index, path)| (RenderPart::Slice { index: index as u32, count }, path))
+            .collect();
+        parts.push((RenderPart::Audio, &audio));
+        let outcomes = join_all(parts.into_iter().map(|(part, path)| async move {
+            let extra = slots.acquire().await;
+            let outcome = if abort.load(Ordering::Relaxed) || self.request_cancel.load(Ordering::Relaxed) {
+                Ok(ChildOutcome::Canceled)
+            } else {
+                self.run_child(part, path, progress, abort).await
+            };
+            slots.release(extra).await;
+            // One failed part makes the others pointless
+            if !matches!(outcome, Ok(ChildOutcome::Done { .. })) {
+                abort.store(true, Ordering::Relaxed);
+            }
+            outcome
+        }))
+        .await;
+
+        let mut canceled = false;
+        let mut failure = None;
+        let mut logs = String::new();
+        for (index, outcome) in outcomes.into_iter().enumerate() {
+            match outcome {
+                Ok(ChildOutcome::Done { output, .. }) => {
+                    let _ = writeln!(&mut logs, "===== Part {index} =====\n{output}");
+                }
+                Ok(ChildOutcome::Canceled) => canceled = true,
+                Ok(ChildOutcome::Failed(error)) => failure = Some(error),
+                Err(err) => failure = Some(Failure::other(format!("{err:?}"))),
+            }
+        }
+        let outcome = if let Some(error) = failure {
+            ChildOutcome::Failed(error)
+        } else if canceled {
+            ChildOutcome::Canceled
+        } else {
+            *self.status.lock().await = TaskStatus::Finalizing;
+            let list = dir.join("slices.txt");
+            let config = self.params.config.clone();
+            let output = self.output.clone();
+            tokio::task::spawn_blocking(move || {
+                write_concat_list(&list, &slices)?;
+                let Some(ffmpeg) = find_ffmpeg()? else {
+                    bail!(ErrorKind::FfmpegMissing)
+                };
+                mux_video_parts(&ffmpeg, &list, &audio, &config, &output)
+            })
+            .await??;
+            ChildOutcome::Done { duration: None, output: logs }
+        };
+
+        // Slices are not resumable, so there's no point in keeping them around
+        if let Err(err) = tokio::fs::remove_dir_all(&dir).await {
+            warn!("Failed to remove {}: {err:?}", dir.display());
+        }
+        Ok(outcome)
+    }
+
+    /// Runs the analysis pass and then the full render, which encodes using its statistics.
+    async fn run_two_pass(&self, progress: &Mutex<Progress>, abort: &AtomicBool) -> Result<ChildOutcome> {
+        let outcome = match self.run_child(RenderPart::Analysis, &self.output, progress, abort).await? {
+            ChildOutcome::Done { .. } => self.run_child(RenderPart::Full, &self.output, progress, abort).await?,
+            outcome => outcome,
+        };
+        if let Err(err) = remove_pass_logs(&self.output) {
+            warn!("Failed to remove first pass statistics of task #{}: {err:?}", self.id);
+        }
+        Ok(outcome)
+    }
+
+    async fn run_child(&self, part: RenderPart, output: &Path, progress: &Mutex<Progress>, abort: &AtomicBool) -> Result<ChildOutcome> {
+        let mut params = serde_json::to_value(&self.params)?;
+        params["part"] = serde_json::to_value(part)?;
+        if part != RenderPart::Audio && !self.params.config.audio_only() {
+            match shared_probe(false).await {
+                Ok(probe) => params["encoders"] = serde_json::to_value(probe)?,
+                // The child probes by itself and reports the error properly
+                Err(err) => warn!("Task #{}: failed to probe encoders: {err:?}", self.id),
+            }
+        }
+
         let mut child = tokio::process::Command::new(std::env::current_exe()?)
             .arg("render")
             .arg(ASSET_PATH.get().unwrap())
             .stdin(Stdio::piped())
             .stdout(Stdio::piped())
             .stderr(Stdio::piped())
+            .kill_on_drop(true)
             .spawn()?;
         let mut stdin = child.stdin.take().unwrap();
         let stdout = child.stdout.take().unwrap();
+        let mut stderr = child.stderr.take().unwrap();
 
         stdin
-            .write_all(format!("{}\n", serde_json::to_string(&self.params)?).as_bytes())
+            .write_all(format!("{}\n", serde_json::to_string(&params)?).as_bytes())
             .await?;
         stdin
-            .write_all(format!("{}\n", serde_json::to_string(&self.output)?).as_bytes())
+            .write_all(format!("{}\n", serde_json::to_string(output)?).as_bytes())
             .await?;
         stdin.flush().await?;
 
+        // Drained concurrently, a full stderr pipe would block the child
+        let stderr = tokio::spawn(async move {
+            let mut buf = Vec::new();
+            let _ = stderr.read_to_end(&mut buf).await;
+            String::from_utf8(buf).unwrap_or_else(|_| "Invalid output".to_owned())
+        });
+
         let mut lines = BufReader::new(stdout).lines();
-        let mut total = 0;
-        let mut frame_count: u64 = 0;
-        let start = Instant::now();
-        //let mut frame_times = VecDeque::new();
-        //let mut last_update_fps_sec: u32 = 0;
-        let mut last_fps: usize = 0;
-        loop {
-            let line = lines.next_line().await?;
-            let Some(line) = line else { break };
-            let Ok(event): Result<IPCEvent, _> = serde_json::from_str(line.trim()) else {
-                continue;
-            };
-            match event {
-                IPCEvent::StartMixing => {
-                    *self.status.lock().await = TaskStatus::Mixing;
+        let mut log = String::new();
+        let handshake = match tokio::time::timeout(HANDSHAKE_TIMEOUT, handshake(&mut lines, &mut log)).await {
+            Ok(result) => result,
+            Err(_) => Err(anyhow!("no handshake from the render process within {}s", HANDSHAKE_TIMEOUT.as_secs())),
+        };
+        if let Err(err) = handshake {
+            let _ = child.kill().await;
+            let stderr = stderr.await.unwrap_or_default();
+            return Ok(ChildOutcome::Failed(Failure::other(format!("{err:#}\n\n{stderr}"))));
+        }
+
+        let pid = child.id().unwrap_or_default();
+        let mut duration = None;
+        let mut error = None;
+        while let Some(line) = lines.next_line().await? {
+            match ipc::server::decode::<IPCEvent>(&line) {
+                Some(Ok(IPCEvent::StartMixing)) => {
+                    let mut status = self.status.lock().await;
+                    if matches!(*status, TaskStatus::Loading) {
+                        *status = TaskStatus::Mixing;
+                    }
+                }
+                Some(Ok(IPCEvent::StartRender(total))) => {
+                    let mut progress = progress.lock().await;
+                    progress.total += total;
+                    *self.status.lock().await = progress.status();
                 }
-                IPCEvent::StartRender(total_frame) => {
-                    *self.status.lock().await = TaskStatus::Rendering {
-                        progress: 0.,
-                        fps: 0,
-                        estimate: 0.,
-                    };
-                    total = total_frame;
+                Some(Ok(IPCEvent::Frame { elapsed })) => {
+                    let mut progress = progress.lock().await;
+                    progress.frame(pid, elapsed);
+                    *self.status.lock().await = progress.status();
                 }
-                IPCEvent::Frame => {
-                    frame_count += 1;
-                    if frame_count % 10 == 0 {
-                        let now = Instant::now();
-                        let mut last_update = self.last_fps_update.lock().await;
-                        let elapsed = now.duration_since(*last_update).as_secs_f64();
-                        if elapsed > 0.0 {
-                            last_fps = (10.0 / elapsed) as usize;
-                        }
-                        *last_update = now;
+                Some(Ok(IPCEvent::StartFinalizing)) => {
+                    // Other slices may still be rendering
+                    let mut progress = progress.lock().await;
+                    progress.finish(pid);
+                    if progress.frames >= progress.total {
+                        *self.status.lock().await = TaskStatus::Finalizing;
                     }
-
-                    let cur = start.elapsed().as_secs_f64();
-                    let estimate = total.saturating_sub(frame_count).max(1) as f64 / last_fps.max(1) as f64;
-
-                    if frame_count as f64 / total as f64 >= 1.0 {
-                        let output = child.wait_with_output().await?;
-                        let stdout = String::from_utf8(output.stdout)
-                            .unwrap_or_else(|_| "Invalid output".to_owned());
-                        let stderr = String::from_utf8(output.stderr)
-                            .unwrap_or_else(|_| "Invalid output".to_owned());
-                        *self.status.lock().await = TaskStatus::Done {
-                            duration: cur,
-                            output: format!("[STDOUT]\n{stdout}\n\n[STDERR]\n{stderr}"),
-                        };
-                        return Ok(());
-                    } else {
-                        *self.status.lock().await = TaskStatus::Rendering {
-                            progress: frame_count as f64 / total as f64,
-                            fps: last_fps as u64,
-                            estimate,
-                        };
+                }
+                Some(Ok(IPCEvent::Encoder(encoder))) => *self.encoder.lock().await = Some(encoder),
+                Some(Ok(IPCEvent::Loudness(report))) => *self.loudness.lock().await = Some(report),
+                Some(Ok(IPCEvent::Warning(warning))) => self.warnings.lock().await.push(warning),
+                Some(Ok(IPCEvent::Error {
+                    kind,
+                    message,
+                    context_chain,
+                    hint,
+                })) => {
+                    let mut error_message = message;
+                    for cause in context_chain {
+                        let _ = write!(&mut error_message, "\n  caused by: {cause}");
                     }
+                    error = Some(Failure {
+                        error: error_message,
+                        kind,
+                        hint,
+                    });
+                }
+                Some(Ok(IPCEvent::Done(secs))) => duration = Some(secs),
+                Some(Ok(IPCEvent::Hello { .. })) => {}
+                Some(Err(err)) => {
+                    warn!("Task #{}: damaged IPC frame: {err:?}", self.id);
+                    log.push_str(&line);
+                    log.push('\n');
                 }
-                IPCEvent::Done(duration) => {
-                    let output = child.wait_with_output().await?;
-                    let stdout = String::from_utf8(output.stdout)
-                        .unwrap_or_else(|_| "Invalid output".to_owned());
-                    let stderr = String::from_utf8(output.stderr)
-                        .unwrap_or_else(|_| "Invalid output".to_owned());
-                    *self.status.lock().await = TaskStatus::Done {
-                        duration,
-                        output: format!("[STDOUT]\n{stdout}\n\n[STDERR]\n{stderr}"),
-                    };
-                    return Ok(());
+                None => {
+                    log.push_str(&line);
+                    log.push('\n');
                 }
             }
-            if self.request_cancel.load(Ordering::Relaxed) {
+            if self.request_cancel.load(Ordering::Relaxed) || abort.load(Ordering::Relaxed) {
                 child.kill().await?;
-                *self.status.lock().await = TaskStatus::Canceled;
-                return Ok(());
+                return Ok(ChildOutcome::Canceled);
             }
         }
 
-        let output = child.wait_with_output().await?;
-        if !output.status.success() {
-            *self.status.lock().await = TaskStatus::Failed {
-                error: format!(
-                    "Child process exited abnormally ({:?})\n\n{}",
-                    output.status.code(),
-                    String::from_utf8(output.stderr)?
-                ),
+        // A child that failed never announces finalizing
+        progress.lock().await.finish(pid);
+        let status = child.wait().await?;
+        let stderr = stderr.await.unwrap_or_default();
+        if !status.success() {
+            let failure = match error {
+                Some(Failure { mut error, kind, hint }) => {
+                    let _ = write!(&mut error, "\n\n{stderr}");
+                    Failure { error, kind, hint }
+                }
+                None => Failure::other(format!("Child process exited abnormally ({:?})\n\n{stderr}", status.code())),
             };
-            return Ok(());
+            return Ok(ChildOutcome::Failed(failure));
         }
-
-        Ok(())
+        Ok(ChildOutcome::Done {
+            duration,
+            output: format!("[STDOUT]\n{log}\n\n[STDERR]\n{stderr}"),
+        })
     }
 
     pub fn cancel(&self) {
@@ -235,6 +565,9 @@ impl Task {
             path: self.params.path.display().to_string(),
             cover: self.cover.path().display().to_string(),
             status: self.status.lock().await.clone(),
+            encoder: self.encoder.lock().await.clone(),
+            loudness: *self.loudness.lock().await,
+            warnings: self.warnings.lock().await.clone(),
         }
     }
 }
@@ -247,42 +580,283 @@ pub struct TaskView {
     path: String,
     cover: String,
     status: TaskStatus,
+    encoder: Option<String>,
+    loudness: Option<LoudnessReport>,
+    warnings: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase", default)]
+pub struct QueueSettings {
+    pub max_concurrent_renders: usize,
+    /// Per-encoder caps keyed by the vendor of the encoder a task ends up with, named like
+    /// `RenderConfig::encoder`, so `auto` counts against the vendor it resolves to. Outputs that
+    /// don't encode video count as `"cpu"`. E.g. `{ "nvenc": 3 }` since consumer NVIDIA drivers
+    /// limit concurrent NVENC sessions.
+    pub encoder_limits: HashMap<String, usize>,
+}
+
+impl Default for QueueSettings {
+    fn default() -> Self {
+        Self {
+            max_concurrent_renders: 1,
+            encoder_limits: HashMap::new(),
+        }
+    }
+}
+
+impl QueueSettings {
+    fn file() -> PathBuf {
+        CONFIG_DIR.get().unwrap().join("queue.json")
+    }
+
+    fn load() -> Result<Self> {
+        let file = Self::file();
+        Ok(if file.exists() {
+            serde_json::from_reader(std::io::BufReader::new(std::fs::File::open(file)?))?
+        } else {
+            Self::default()
+        })
+    }
+
+    fn save(&self) -> Result<()> {
+        serde_json::to_writer(std::io::BufWriter::new(std::fs::File::create(Self::file())?), self)?;
+        Ok(())
+    }
+}
+
+fn encoder_key(task: &Task) -> &str {
+    task.encoder_key.get().map_or("cpu", String::as_str)
+}
+
+/// The vendor of the encoder a render of `config` picks, chosen from the shared probe the same
+/// way the render process does. Without a probe, the preference is taken as it is.
+async fn resolve_encoder_key(config: &RenderConfig) -> String {
+    if config.audio_only() || config.image_sequence() || config.transparent {
+        return "cpu".to_owned();
+    }
+    let preference = if config.hardware_accel { config.encoder.as_str() } else { "cpu" };
+    let probe = match shared_probe(false).await {
+        Ok(probe) => probe,
+        Err(err) => {
+            warn!("Failed to probe encoders, scheduling by the preference: {err:?}");
+            return preference.to_owned();
+        }
+    };
+    let candidates = encoder::candidates(&config.video_codec, preference);
+    candidates
+        .iter()
+        .find(|it| probe.available.contains(it.name))
+        .or(candidates.last())
+        .map_or(preference, |it| it.vendor.config_name())
+        .to_owned()
+}
+
+#[derive(Default)]
+struct Scheduler {
+    settings: QueueSettings,
+    pending: VecDeque<Arc<Task>>,
+    running: usize,
+    running_by_encoder: HashMap<String, usize>,
+}
+
+impl Scheduler {
+    /// Whether one more render process using the encoder `key` fits in the limits.
+    fn fits(&self, key: &str) -> bool {
+        if self.running >= self.settings.max_concurrent_renders.max(1) {
+            return false;
+        }
+        match self.settings.encoder_limits.get(key) {
+            Some(&limit) => self.running_by_encoder.get(key).copied().unwrap_or_default() < limit,
+            None => true,
+        }
+    }
+
+    /// Takes the oldest pending task that fits in the concurrency limits. Tasks blocked by an
+    /// encoder limit are skipped, so a full NVENC doesn't hold up a CPU render queued after it.
+    fn next_runnable(&mut self) -> Option<Arc<Task>> {
+        let index = self.pending.iter().position(|task| self.fits(encoder_key(task)))?;
+        let task = self.pending.remove(index)?;
+        self.acquire(encoder_key(&task));
+        Some(task)
+    }
+
+    fn acquire(&mut self, key: &str) {
+        self.running += 1;
+        *self.running_by_encoder.entry(key.to_owned()).or_default() += 1;
+    }
+
+    /// Takes a slot for another render process of a running task, see [`Slots`].
+    fn try_acquire(&mut self, key: &str) -> bool {
+        let fits = self.fits(key);
+        if fits {
+            self.acquire(key);
+        }
+        fits
+    }
+
+    fn release(&mut self, key: &str) {
+        self.running -= 1;
+        if let Some(count) = self.running_by_encoder.get_mut(key) {
+            *count -= 1;
+        }
+    }
+}
+
+/// Wakes the worker and every task waiting in [`Slots::acquire`] after a slot was released.
+fn slot_released(notify: &Notify) {
+    notify.notify_one();
+    notify.notify_waiters();
+}
+
+/// Scheduler slots for the render processes of one task. A task starts with one slot, the one it
+/// was scheduled with; each further process that runs at the same time takes its own, so sliced
+/// renders stay within `max_concurrent_renders` and the encoder limits.
+struct Slots<'a> {
+    scheduler: &'a Mutex<Scheduler>,
+    notify: &'a Notify,
+    key: &'a str,
+    /// Whether the task's own slot is free.
+    own: AtomicBool,
+}
+
+impl<'a> Slots<'a> {
+    fn new(scheduler: &'a Mutex<Scheduler>, notify: &'a Notify, key: &'a str) -> Self {
+        Self {
+            scheduler,
+            notify,
+            key,
+            own: AtomicBool::new(true),
+        }
+    }
+
+    /// Waits for a free slot, returns whether it is an extra one that has to go back to the
+    /// scheduler.
+    async fn acquire(&self) -> bool {
+        loop {
+            // Registered before checking, so a release in between isn't missed
+            let notified = self.notify.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+            if self.own.swap(false, Ordering::AcqRel) {
+                return false;
+            }
+            if self.scheduler.lock().await.try_acquire(self.key) {
+                return true;
+            }
+            notified.await;
+        }
+    }
+
+    async fn release(&self, extra: bool) {
+        if extra {
+            self.scheduler.lock().await.release(self.key);
+        } else {
+            self.own.store(true, Ordering::Release);
+        }
+        slot_released(self.notify);
+    }
 }
 
 pub struct TaskQueue {
-    sender: mpsc::UnboundedSender<Arc<Task>>,
+    scheduler: Arc<Mutex<Scheduler>>,
+    notify: Arc<Notify>,
     worker: JoinHandle<()>,
 
-    tasks: Mutex<Vec<Arc<Task>>>,
+    tasks: Arc<Mutex<Vec<Arc<Task>>>>,
 }
 impl TaskQueue {
     pub fn new() -> Self {
-        let (sender, mut receiver) = mpsc::unbounded_channel::<Arc<Task>>();
-        let task = tokio::spawn(async move {
-            while let Some(task) = receiver.recv().await {
-                if let Err(err) = task.run().await {
-                    error!("Failed to render: {err:?}");
-                    *task.status.lock().await = TaskStatus::Failed {
-                        error: format!("{err:?}"),
-                    };
+        let scheduler: Arc<Mutex<Scheduler>> = Arc::default();
+        let notify = Arc::new(Notify::new());
+        let tasks: Arc<Mutex<Vec<Arc<Task>>>> = Arc::default();
+        let worker = tokio::spawn({
+            let scheduler = Arc::clone(&scheduler);
+            let notify = Arc::clone(&notify);
+            let tasks = Arc::clone(&tasks);
+            async move {
+                loop {
+                    while let Some(task) = scheduler.lock().await.next_runnable() {
+                        tokio::spawn(Self::run_task(task, Arc::clone(&scheduler), Arc::clone(&notify), Arc::clone(&tasks)));
+                    }
+                    notify.notified().await;
                 }
             }
         });
 
         Self {
-            sender,
-            worker: task,
+            scheduler,
+            notify,
+            worker,
+
+            tasks,
+        }
+    }
 
-            tasks: Mutex::default(),
+    async fn run_task(task: Arc<Task>, scheduler: Arc<Mutex<Scheduler>>, notify: Arc<Notify>, tasks: Arc<Mutex<Vec<Arc<Task>>>>) {
+        if task.request_cancel.load(Ordering::Relaxed) {
+            *task.status.lock().await = TaskStatus::Canceled;
+        } else if let Err(err) = task.run(&scheduler, &notify).await {
+            error!("Failed to render: {err:?}");
+            let kind = ErrorKind::of(&err);
+            *task.status.lock().await = TaskStatus::Failed {
+                error: format!("{err:?}"),
+                kind,
+                hint: kind.hint(),
+            };
+        }
+        scheduler.lock().await.release(encoder_key(&task));
+        slot_released(&notify);
+        if let Err(err) = save_tasks(&tasks.lock().await).await {
+            error!("Failed to save task queue: {err:?}");
         }
     }
 
+    async fn enqueue(&self, task: Arc<Task>) {
+        if task.encoder_key.get().is_none() {
+            let _ = task.encoder_key.set(resolve_encoder_key(&task.params.config).await);
+        }
+        self.scheduler.lock().await.pending.push_back(task);
+        self.notify.notify_one();
+    }
+
+    /// Loads the queue and settings saved by a previous session. Unfinished tasks are queued again.
+    pub async fn restore(&self) -> Result<()> {
+        // Broken settings shouldn't cost the restored tasks
+        let settings = QueueSettings::load().unwrap_or_else(|err| {
+            warn!("Failed to load queue settings, using the defaults: {err:?}");
+            QueueSettings::default()
+        });
+        self.scheduler.lock().await.settings = settings;
+
+        let file = tasks_file();
+        if !file.exists() {
+            return Ok(());
+        }
+        let records: Vec<TaskRecord> = serde_json::from_slice(&tokio::fs::read(&file).await?)
+            .with_context(|| format!("failed to parse {}", file.display()))?;
+
+        let mut guard = self.tasks.lock().await;
+        for record in records {
+            let id = guard.len() as u32;
+            let task = Arc::new(Task::restore(id, record).await?);
+            let pending = matches!(*task.status.lock().await, TaskStatus::Pending);
+            guard.push(Arc::clone(&task));
+            if pending {
+                self.enqueue(task).await;
+            }
+        }
+        info!("Restored {} tasks", guard.len());
+        save_tasks(&guard).await
+    }
+
     pub async fn post(&self, params: RenderParams, output_path: Option<PathBuf>) -> Result<u32> {
         let mut guard = self.tasks.lock().await;
         let id = guard.len() as u32;
         let task = Arc::new(Task::new(id, params, output_path).await?);
         guard.push(Arc::clone(&task));
-        self.sender.send(task)?;
+        self.enqueue(task).await;
+        save_tasks(&guard).await?;
 
         Ok(id)
     }
@@ -297,8 +871,32 @@ impl TaskQueue {
         result
     }
 
-    pub async fn cancel(&self, id: u32) {
-        self.tasks.lock().await[id as usize].cancel();
+    pub async fn cancel(&self, id: u32) -> Result<()> {
+        let guard = self.tasks.lock().await;
+        let task = guard.get(id as usize).with_context(|| format!("no task #{id}"))?;
+        task.cancel();
+
+        // Pending tasks can be dropped right away instead of waiting for a free slot
+        let mut scheduler = self.scheduler.lock().await;
+        let before = scheduler.pending.len();
+        scheduler.pending.retain(|it| !Arc::ptr_eq(it, task));
+        if scheduler.pending.len() != before {
+            drop(scheduler);
+            *task.status.lock().await = TaskStatus::Canceled;
+            save_tasks(&guard).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn settings(&self) -> QueueSettings {
+        self.scheduler.lock().await.settings.clone()
+    }
+
+    pub async fn set_settings(&self, settings: QueueSettings) -> Result<()> {
+        settings.save()?;
+        self.scheduler.lock().await.settings = settings;
+        self.notify.notify_one();
+        Ok(())
     }
 }
 
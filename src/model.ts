@@ -29,6 +29,9 @@ export type TaskStatus =
       fps: number;
       estimate: number;
     }
+  | {
+      type: 'finalizing';
+    }
   | {
       type: 'done';
       duration: number;
@@ -40,8 +43,12 @@ export type TaskStatus =
   | {
       type: 'failed';
       error: string;
+      kind: ErrorKind;
+      hint: string | null;
     };
 
+export type ErrorKind = 'chart_load' | 'ffmpeg_missing' | 'encoder_unavailable' | 'invalid_config' | 'disk_full' | 'other';
+
 export interface Task {
   id: number;
   name: string;
@@ -49,6 +56,19 @@ export interface Task {
   path: string;
   cover: string;
   status: TaskStatus;
+  encoder: string | null;
+  loudness: LoudnessReport | null;
+  warnings: string[];
+}
+
+export interface LoudnessReport {
+  // LUFS, null for silence
+  integrated: number | null;
+  // dBTP, null for silence
+  truePeak: number | null;
+  // dB applied by loudness normalization
+  gain: number;
+  limited: boolean;
 }
 
 export interface RenderConfig {
@@ -105,6 +125,69 @@ export interface RenderConfig {
   uiPause: boolean;
   bar: boolean;
   ffmpegThread: boolean;
+  // Seconds per resumable chunk, 0 writes a single stream
+  segmentLength?: number;
+  // Time ranges rendered by parallel processes, 1 renders in one process
+  renderSlices?: number;
+  // 'audio' exports only the mixed track, 'frames' a folder of images plus a WAV
+  outputMode?: 'video' | 'audio' | 'frames';
+  imageFormat?: 'png' | 'exr' | 'webp';
+  // Keep the alpha channel and drop the illustration
+  transparent?: boolean;
+  alphaCodec?: 'prores' | 'qtrle' | 'vp9';
+  // SVT-AV1 -preset, 0 to 13
+  svtAv1Preset?: number;
+  // rav1e -speed, 0 to 10
+  rav1eSpeed?: number;
+  // Used by 'VBR', empty for 1.5x bitrate
+  maxBitrate?: string;
+  // Used by 'VBR', empty for 2x bitrate
+  rateBuffer?: string;
+  // Output size in MB, used by 'TargetSize'
+  targetSize?: number;
+  // 8 or 10, 10-bit needs HEVC or AV1
+  bitDepth?: number;
+  hdr?: boolean;
+  // 'bt709' or 'bt601', ignored for HDR
+  colorSpace?: string;
+  // 'limited' or 'full'
+  colorRange?: string;
+  // Split like a shell would, quote values with spaces
+  extraInputArgs?: string;
+  extraOutputArgs?: string;
+  // Filter chain run before the color conversion
+  extraVideoFilters?: string;
+  // 0 mixes at 48 kHz for videos and at targetAudio otherwise
+  mixSampleRate?: number;
+  positionalHitsound?: boolean;
+  // Pan at the screen edges, from 0 to 1
+  hitsoundPan?: number;
+  limiter?: boolean;
+  // dBFS
+  limiterCeiling?: number;
+  loudnessNormalize?: boolean;
+  // Integrated loudness target, e.g. -14 for streaming platforms
+  targetLufs?: number;
+  volumeClick?: number;
+  volumeDrag?: number;
+  volumeHold?: number;
+  volumeFlick?: number;
+  hitsoundDir?: string;
+  holdSounds?: boolean;
+}
+
+export interface QueueSettings {
+  maxConcurrentRenders: number;
+  // Keyed by RenderConfig.encoder ('cpu' when hardware acceleration is off)
+  encoderLimits: Record<string, number>;
+}
+
+export interface EncoderProbe {
+  key: { ffmpeg: string; ffmpegVersion: string; driverFingerprint: string };
+  // ffmpeg encoder names, e.g. 'hevc_nvenc'
+  detected: string[];
+  available: string[];
+  errors: Record<string, string>;
 }
 
 export interface RPEChart {